@@ -1,30 +1,57 @@
 use std::env;
 use std::fs::File;
-use std::io::{Read, Error, ErrorKind};
+use std::io::{IsTerminal, Read, Error, ErrorKind};
+use salt::diagnostics::{self, Diagnostic, Renderer};
 use salt::parser::Parser;
 use salt::eval;
 use salt::types;
-use salt::utils::*;
+
+fn fail(renderer: &Renderer, diag: &Diagnostic, filename: &str, source: &str) -> ! {
+    eprint!("{}", renderer.render(diag, filename, source));
+    std::process::exit(1);
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut color = std::io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+    let mut lexical = false;
+    let mut checked = false;
     let filename = {
-        let mut args = env::args();
-        args.next();
-        args.next().ok_or(Error::new(ErrorKind::NotFound, "usage: cargo run --bin interp <filename>"))?
+        let mut args = env::args().skip(1).filter(|arg| match arg.as_str() {
+            "--no-color" => {
+                color = false;
+                false
+            }
+            "--lexical" => {
+                lexical = true;
+                false
+            }
+            "--checked" => {
+                checked = true;
+                false
+            }
+            _ => true,
+        });
+        args.next().ok_or(Error::new(ErrorKind::NotFound, "usage: cargo run --bin interp [--no-color] [--lexical] [--checked] <filename>"))?
     };
+    let renderer = Renderer { color };
 
     let mut contents = String::new();
-    File::open(filename)?.read_to_string(&mut contents)?;
+    File::open(&filename)?.read_to_string(&mut contents)?;
 
-    let mut e = Parser::new(&contents[..])
-        .parse()
-        .map_err(|err| Error::new(ErrorKind::Other, format!("{:?}", err)))?;
+    let mut program = Parser::new(&contents[..])
+        .parse_program()
+        .unwrap_or_else(|err| fail(&renderer, &diagnostics::parse_error(&err), &filename, &contents));
 
-        types::TypeContext::new()
-        .type_expr(&mut e)
-        .map_err(|err| Error::new(ErrorKind::Other, format!("{:?}", err)))?;
+    let mut tcx = types::TypeContext::new();
+    tcx.lexical = lexical;
+    if let Err(err) = tcx.type_program(&mut program) {
+        fail(&renderer, &diagnostics::type_error(&err, &tcx), &filename, &contents);
+    }
 
-    eval::Context::default().eval_expr(&e, Lifetime::global());
+    let mut ctx = if checked { eval::Context::checked() } else { eval::Context::default() };
+    if let Err(err) = ctx.eval_program(&program) {
+        fail(&renderer, &diagnostics::runtime_error(&err), &filename, &contents);
+    }
 
     Ok(())
 }
@@ -9,9 +9,7 @@ fn main() {
 
 // 🧪 Evaluator test
 fn test_eval_block() {
-    let mut ctx = Context {
-        store: Default::default(),
-    };
+    let mut ctx = Context::default();
 
     let lifetime = Lifetime::global();
 
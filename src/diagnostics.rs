@@ -0,0 +1,276 @@
+use std::fmt::Write;
+use crate::eval;
+use crate::lexer;
+use crate::parser;
+use crate::types::{self, TypeContext};
+use crate::utils::*;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    pub primary: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub code: Option<&'static str>,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            code: None,
+            message: message.into(),
+            labels: vec![],
+            notes: vec![],
+        }
+    }
+
+    pub fn code(mut self, code: &'static str) -> Diagnostic {
+        self.code = Some(code);
+        self
+    }
+
+    fn label(mut self, span: Option<Span>, message: impl Into<String>, primary: bool) -> Diagnostic {
+        if let Some(span) = span {
+            self.labels.push(Label { span, message: message.into(), primary });
+        }
+        self
+    }
+
+    pub fn primary(self, span: Option<Span>, message: impl Into<String>) -> Diagnostic {
+        self.label(span, message, true)
+    }
+
+    pub fn secondary(self, span: Option<Span>, message: impl Into<String>) -> Diagnostic {
+        self.label(span, message, false)
+    }
+
+    pub fn note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+}
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+// Renders diagnostics in the style of rustc. Colors can be turned off so the
+// output is stable enough to compare against in tests.
+#[derive(Clone, Debug)]
+pub struct Renderer {
+    pub color: bool,
+}
+
+impl Renderer {
+    pub fn plain() -> Renderer {
+        Renderer { color: false }
+    }
+
+    pub fn colored() -> Renderer {
+        Renderer { color: true }
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+
+    pub fn render(&self, diag: &Diagnostic, filename: &str, source: &str) -> String {
+        let mut out = String::new();
+        let header = match diag.code {
+            Some(code) => format!("error[{}]", code),
+            None => String::from("error"),
+        };
+        let _ = writeln!(out, "{}{}", self.paint(RED, &header), self.paint(BOLD, &format!(": {}", diag.message)));
+
+        let mut labels: Vec<&Label> = diag.labels.iter().collect();
+        labels.sort_by_key(|label| (label.span.line, label.span.col));
+        let width = labels.iter().map(|label| label.span.line.to_string().len()).max().unwrap_or(1);
+        let gutter = " ".repeat(width);
+        let pipe = self.paint(BLUE, "|");
+
+        if let Some(first) = diag.labels.iter().find(|label| label.primary).or(labels.first().copied()) {
+            let arrow = self.paint(BLUE, "-->");
+            let _ = writeln!(out, "{}{} {}:{}:{}", gutter, arrow, filename, first.span.line, first.span.col);
+            let _ = writeln!(out, "{} {}", gutter, pipe);
+        }
+
+        let lines: Vec<&str> = source.lines().collect();
+        let mut prev_line = None;
+        for label in &labels {
+            let line_no = label.span.line;
+            let text = lines.get(line_no.wrapping_sub(1)).copied().unwrap_or("");
+            if prev_line != Some(line_no) {
+                let number = self.paint(BLUE, &format!("{:>width$}", line_no, width = width));
+                let _ = writeln!(out, "{} {} {}", number, pipe, text);
+            }
+            prev_line = Some(line_no);
+
+            // Columns and spans count bytes, but the underline needs one
+            // mark per character.
+            let start = label.span.col.saturating_sub(1).min(text.len());
+            let end = (start + label.span.end - label.span.start).min(text.len());
+            let len = text.get(start..end).map_or(end - start, |s| s.chars().count()).max(1);
+            let start = text.get(..start).map_or(start, |s| s.chars().count());
+            let (mark, style) = if label.primary { ("^", RED) } else { ("-", BLUE) };
+            let underline = self.paint(style, &format!("{} {}", mark.repeat(len), label.message));
+            let _ = writeln!(out, "{} {} {}{}", gutter, pipe, " ".repeat(start), underline.trim_end());
+        }
+
+        if !labels.is_empty() && !diag.notes.is_empty() {
+            let _ = writeln!(out, "{} {}", gutter, pipe);
+        }
+        for note in &diag.notes {
+            let _ = writeln!(out, "{} {} {}: {}", gutter, self.paint(BLUE, "="), self.paint(BOLD, "note"), note);
+        }
+        out
+    }
+}
+
+pub fn parse_error(err: &parser::Error) -> Diagnostic {
+    let span = Some(err.span());
+    match err {
+        parser::Error::EndOfFile(_) => Diagnostic::error("unexpected end of file")
+            .primary(span, "expected more input"),
+        parser::Error::Lexer(lexer::Error::Unknown(_)) => Diagnostic::error("unknown start of token")
+            .primary(span, "not recognised"),
+        parser::Error::Lexer(lexer::Error::IntOverflow(_)) => Diagnostic::error("literal out of range for `i32`")
+            .primary(span, "does not fit in an `i32`")
+            .note("`i32` ranges from -2147483648 to 2147483647"),
+        parser::Error::Unexpected(tok, _) => Diagnostic::error(format!("unexpected token `{}`", tok))
+            .primary(span, "unexpected token"),
+    }
+}
+
+// Builds a diagnostic for a type error. The context the error was raised in
+// supplies secondary labels pointing at the conflicting borrows.
+pub fn type_error(err: &types::Error, ctx: &TypeContext) -> Diagnostic {
+    use types::Error::*;
+    let span = err.span();
+    let loan = |holder: &Ident| ctx.loans.get(holder).copied();
+    let moved = |lval: &Lval| ctx.moves.get(&lval.ident).copied();
+    match err.kind() {
+        Dummy | At(..) => Diagnostic::error("internal type checker error"),
+        UnboundVar(x) => Diagnostic::error(format!("cannot find value `{}` in this scope", x))
+            .code("E0425")
+            .primary(span, "not found in this scope"),
+        InvalidMove => Diagnostic::error("cannot move out of a reference or a moved value")
+            .code("E0507")
+            .primary(span, "move occurs here"),
+        InvalidWrite => Diagnostic::error("cannot assign through a `&` reference")
+            .code("E0594")
+            .primary(span, "cannot assign"),
+        InvalidDeref(lval) => Diagnostic::error(format!("`{}` cannot be dereferenced", lval))
+            .code("E0614")
+            .primary(span, "not a box or reference"),
+        IncompatibleTypes(t1, t2) => Diagnostic::error("mismatched types")
+            .code("E0308")
+            .primary(span, format!("expected `{}`, found `{}`", t1, t2)),
+        UseAfterMove(lval) if span.is_some() && moved(lval) == span => Diagnostic::error(format!("use of moved value: `{}`", lval))
+            .code("E0382")
+            .primary(span, "value moved here, in previous iteration of loop"),
+        UseAfterMove(lval) => Diagnostic::error(format!("use of moved value: `{}`", lval))
+            .code("E0382")
+            .primary(span, "value used here after move")
+            .secondary(moved(lval), "value moved here"),
+        MovedBorrow(lval) => Diagnostic::error(format!("borrow of moved value: `{}`", lval))
+            .code("E0382")
+            .primary(span, "value borrowed here after move")
+            .secondary(moved(lval), "value moved here"),
+        ImmutableBorrow(lval) => Diagnostic::error(format!("cannot borrow `{}` as mutable, as it is behind a `&` reference", lval))
+            .code("E0596")
+            .primary(span, "cannot borrow as mutable"),
+        ImmutableBinding(lval, false) => Diagnostic::error(format!("cannot assign twice to immutable variable `{}`", lval))
+            .code("E0384")
+            .primary(span, "cannot assign twice to immutable variable")
+            .note(format!("consider making this binding mutable: `mut {}`", lval)),
+        ImmutableBinding(lval, true) => Diagnostic::error(format!("cannot borrow `{}` as mutable, as it is not declared as mutable", lval))
+            .code("E0596")
+            .primary(span, "cannot borrow as mutable")
+            .note(format!("consider changing this to be mutable: `mut {}`", lval)),
+        Dangling(lval, _) => Diagnostic::error(format!("`{}` does not live long enough", lval.ident))
+            .code("E0597")
+            .primary(span, "borrowed value does not live long enough")
+            .note(format!("`{}` is dropped at the end of this block while still borrowed", lval.ident)),
+        ExpiredBorrow(lval) => Diagnostic::error(format!("borrow held by `{}` used after it ended", lval))
+            .primary(span, "used here after the borrow ended")
+            .note(format!("`{}` was not expected to be used again, so its borrows had already been released", lval)),
+        UnboundFn(name) => Diagnostic::error(format!("cannot find function `{}` in this scope", name))
+            .code("E0425")
+            .primary(span, "not found in this scope"),
+        DuplicateFn(name) => Diagnostic::error(format!("the name `{}` is defined multiple times", name))
+            .code("E0428")
+            .primary(span, format!("`{}` redefined here", name)),
+        MissingMain => Diagnostic::error("`main` function not found")
+            .code("E0601")
+            .note("a program needs a `fn main()` taking no arguments and returning `()`"),
+        ArityMismatch(name, expected, found) => Diagnostic::error(format!("function `{}` takes {} arguments but {} were supplied", name, expected, found))
+            .code("E0061")
+            .primary(span, format!("expected {} arguments", expected)),
+        MissingLifetime(name) => Diagnostic::error("missing lifetime specifier")
+            .code("E0106")
+            .primary(span, format!("`{}` returns a reference", name))
+            .note("this function's return type contains a borrowed value, but the signature does not say which input it is borrowed from")
+            .note(format!("consider introducing a named lifetime parameter: `fn {}<'a>(..) -> &'a ..`", name)),
+        UndeclaredLifetime(name) => Diagnostic::error(format!("use of undeclared lifetime name `'{}`", name))
+            .code("E0261")
+            .primary(span, "undeclared lifetime in the signature of this function"),
+        LifetimeMismatch(name, region @ types::Region::Named(found)) if !region.elided() => Diagnostic::error("lifetime may not live long enough")
+            .primary(span, format!("returning a reference with lifetime `'{}` where `'{}` is required", found, name)),
+        LifetimeMismatch(name, _) => Diagnostic::error("lifetime may not live long enough")
+            .primary(span, format!("returning a reference that does not live for `'{}`", name)),
+        UsedWhileMutablyBorrowed(lval, holder) => Diagnostic::error(format!("cannot use `{}` because it was mutably borrowed", lval))
+            .code("E0503")
+            .primary(span, format!("use of borrowed `{}`", lval.ident))
+            .secondary(loan(holder), format!("`{}` is borrowed here", lval.ident)),
+        AssignedWhileBorrowed(lval, holder) => Diagnostic::error(format!("cannot assign to `{}` because it is borrowed", lval))
+            .code("E0506")
+            .primary(span, format!("`{}` is assigned to here but it was already borrowed", lval))
+            .secondary(loan(holder), format!("`{}` is borrowed here", lval.ident)),
+        MovedWhileBorrowed(lval, holder) => Diagnostic::error(format!("cannot move out of `{}` because it is borrowed", lval))
+            .code("E0505")
+            .primary(span, format!("move out of `{}` occurs here", lval))
+            .secondary(loan(holder), format!("borrow of `{}` occurs here", lval.ident)),
+        BorrowedWhileMutablyBorrowed(lval, holder) => Diagnostic::error(format!("cannot borrow `{}` as immutable because it is also borrowed as mutable", lval))
+            .code("E0502")
+            .primary(span, "immutable borrow occurs here")
+            .secondary(loan(holder), "mutable borrow occurs here"),
+        MutablyBorrowedWhileBorrowed(lval, holder) => Diagnostic::error(format!("cannot borrow `{}` as mutable because it is also borrowed", lval))
+            .code("E0502")
+            .primary(span, "mutable borrow occurs here")
+            .secondary(loan(holder), "first borrow occurs here"),
+    }
+}
+
+pub fn runtime_error(err: &eval::RuntimeError) -> Diagnostic {
+    use eval::RuntimeError::*;
+    let span = err.span();
+    match err.kind() {
+        AssertionFailed(left, right) => Diagnostic::error("assertion `left == right` failed")
+            .primary(span, "assertion failed here")
+            .note(format!("left: {}", left))
+            .note(format!("right: {}", right)),
+        UseAfterFree(loc, sites) => Diagnostic::error(format!("use of freed location `{}`", loc))
+            .primary(span, "freed location used here")
+            .secondary(sites.allocated, "allocated here")
+            .secondary(sites.freed, "freed here"),
+        DoubleFree(loc, sites) => Diagnostic::error(format!("double free of location `{}`", loc))
+            .primary(span, "freed again here")
+            .secondary(sites.allocated, "allocated here")
+            .secondary(sites.freed, "first freed here"),
+        Leaked(leaks) => leaks.iter().fold(Diagnostic::error(err.kind().to_string()), |diag, (loc, sites)| {
+            diag.primary(sites.allocated, format!("`{}` allocated here is never freed", loc))
+        }),
+        e => Diagnostic::error(e.to_string()).primary(span, "while evaluating this"),
+    }
+}
@@ -0,0 +1,124 @@
+use crate::diagnostics::{self, Diagnostic, Renderer};
+use crate::eval::Context;
+use crate::parser::Parser;
+use crate::types::TypeContext;
+use crate::utils::{Lifetime, Span};
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_borrow_conflict() {
+	let src = "fn main() {\n    let mut x = 1;\n    let mut a = &mut x;\n    x = 2;\n    *a = 3;\n}\n";
+	let mut e = Parser::new(src).parse().unwrap();
+	let mut ctx = TypeContext::new();
+	let err = ctx.type_expr(&mut e).unwrap_err();
+	let out = Renderer::plain().render(&diagnostics::type_error(&err, &ctx), "test.rs", src);
+	assert_eq!(out, "\
+error[E0506]: cannot assign to `x` because it is borrowed
+ --> test.rs:4:5
+  |
+3 |     let mut a = &mut x;
+  |                 ------ `x` is borrowed here
+4 |     x = 2;
+  |     ^^^^^ `x` is assigned to here but it was already borrowed
+");
+    }
+
+    #[test]
+    fn render_mismatched_ref_types() {
+	let render = |src: &str| {
+	    let mut program = Parser::new(src).parse_program().unwrap();
+	    let mut ctx = TypeContext::new();
+	    let err = ctx.type_program(&mut program).unwrap_err();
+	    Renderer::plain().render(&diagnostics::type_error(&err, &ctx), "test.rs", src)
+	};
+	let out = render("fn g<'x>(x: &'x i32) -> i32 { x }\nfn main() {}\n");
+	assert!(out.contains("expected `i32`, found `&'x i32`"));
+	let out = render("fn f(a: &i32) {}\nfn main() {\n    let b = Box::new(1);\n    f(&b);\n}\n");
+	assert!(out.contains("expected `&i32`, found `&Box<i32>`"));
+	let out = render("fn main() {\n    let b = Box::new(1);\n    let mut r = &*b;\n    r = 1;\n}\n");
+	assert!(out.contains("expected `&i32`, found `i32`"));
+    }
+
+    #[test]
+    fn render_parse_error() {
+	let src = "fn main() {\n  let mut x 1;\n}";
+	let err = Parser::new(src).parse().unwrap_err();
+	let out = Renderer::plain().render(&diagnostics::parse_error(&err), "test.rs", src);
+	assert_eq!(out, "\
+error: unexpected token `1`
+ --> test.rs:2:13
+  |
+2 |   let mut x 1;
+  |             ^ unexpected token
+");
+    }
+
+    #[test]
+    fn render_notes_and_color() {
+	let span = Span { start: 0, end: 2, line: 1, col: 1 };
+	let diag = Diagnostic::error("bad").code("E0000").primary(Some(span), "here").note("why");
+	let plain = Renderer::plain().render(&diag, "f.rs", "ab");
+	assert_eq!(plain, "error[E0000]: bad\n --> f.rs:1:1\n  |\n1 | ab\n  | ^^ here\n  |\n  = note: why\n");
+	let colored = Renderer::colored().render(&diag, "f.rs", "ab");
+	assert!(colored.contains("\x1b[1;31merror[E0000]\x1b[0m"));
+    }
+
+    #[test]
+    fn render_immutable_assign() {
+	let src = "fn main() {\n    let x = 1;\n    x = 2;\n}\n";
+	let mut e = Parser::new(src).parse().unwrap();
+	let mut ctx = TypeContext::new();
+	let err = ctx.type_expr(&mut e).unwrap_err();
+	let out = Renderer::plain().render(&diagnostics::type_error(&err, &ctx), "test.rs", src);
+	assert_eq!(out, "\
+error[E0384]: cannot assign twice to immutable variable `x`
+ --> test.rs:3:5
+  |
+3 |     x = 2;
+  |     ^^^^^ cannot assign twice to immutable variable
+  |
+  = note: consider making this binding mutable: `mut x`
+");
+    }
+
+    #[test]
+    fn render_use_after_free() {
+	let src = "fn main() {\n    let b = Box::new(1);\n    let r = &*b;\n    { let c = b; };\n    *r\n}\n";
+	let e = Parser::new(src).parse().unwrap();
+	let err = Context::checked().eval_expr(&e, Lifetime::global()).unwrap_err();
+	let out = Renderer::plain().render(&diagnostics::runtime_error(&err), "test.rs", src);
+	assert_eq!(out, "\
+error: use of freed location `loc_0.1`
+ --> test.rs:5:5
+  |
+2 |     let b = Box::new(1);
+  |             ----------- allocated here
+4 |     { let c = b; };
+  |     -------------- freed here
+5 |     *r
+  |     ^^ freed location used here
+");
+    }
+
+    #[test]
+    fn render_non_ascii_underline() {
+	let src = "let é = \"é\";";
+	let diag = Diagnostic::error("bad")
+	    .primary(Some(Span { start: 4, end: 6, line: 1, col: 5 }), "one")
+	    .secondary(Some(Span { start: 9, end: 13, line: 1, col: 10 }), "three");
+	let out = Renderer::plain().render(&diag, "f.rs", src);
+	assert_eq!(out, "error: bad\n --> f.rs:1:5\n  |\n1 | let é = \"é\";\n  |     ^ one\n  |         --- three\n");
+    }
+
+    #[test]
+    fn render_dangling_note() {
+	let src = "fn main() {\n    let a = 1;\n    let mut r = &a;\n    {\n        let b = 2;\n        r = &b;\n    }\n    assert_eq!(*r, 2);\n}\n";
+	let mut e = Parser::new(src).parse().unwrap();
+	let mut ctx = TypeContext::new();
+	let err = ctx.type_expr(&mut e).unwrap_err();
+	let diag = diagnostics::type_error(&err, &ctx);
+	assert_eq!(diag.notes, vec![String::from("`b` is dropped at the end of this block while still borrowed")]);
+    }
+}
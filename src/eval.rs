@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::fmt;
 use crate::utils::*;
 
 // Owned flag (true = we own it, false = borrowed)
@@ -6,57 +7,385 @@ pub type Owned = bool;
 type Location = Ident;
 type Pvalue = Option<Value>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Unit,
     Int(i32),
+    Bool(bool),
     Ref(Location, Owned),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Slot {
     pub value: Pvalue,
     pub lifetime: Lifetime,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    AssertionFailed(Value, Value),
+    UseAfterMove(Lval),
+    DanglingLocation(Location),
+    InvalidDeref(Lval),
+    Overflow(BinOp),
+    DivisionByZero(BinOp),
+    InvalidOperands(BinOp, Value, Value),
+    InvalidCondition(Value),
+    UnboundFn(Ident),
+    UseAfterFree(Location, Sites),
+    Unallocated(Location),
+    DoubleFree(Location, Sites),
+    Leaked(Vec<(Location, Sites)>),
+    At(Box<Error>, Span),
+}
+
+// Where a location was allocated and freed, as far as the sanitizer knows.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sites {
+    pub allocated: Option<Span>,
+    pub freed: Option<Span>,
+}
+
+impl Located for Error {
+    fn wrap(self, span: Span) -> Error {
+        Error::At(Box::new(self), span)
+    }
+
+    fn unwrap_at(&self) -> Option<(&Error, Span)> {
+        match self {
+            Error::At(inner, span) => Some((inner, *span)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Unit => write!(f, "()"),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Ref(loc, true) => write!(f, "Box({})", loc),
+            Value::Ref(loc, false) => write!(f, "&{}", loc),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::AssertionFailed(left, right) => write!(
+                f, "assertion `left == right` failed\n  left: {}\n right: {}", left, right
+            ),
+            Error::UseAfterMove(lval) => write!(f, "use of moved value `{}`", lval),
+            Error::DanglingLocation(loc) => write!(f, "access to freed location `{}`", loc),
+            Error::InvalidDeref(lval) => write!(f, "cannot dereference `{}`", lval),
+            Error::Overflow(op) => {
+                let verb = match op {
+                    BinOp::Add => "add",
+                    BinOp::Sub => "subtract",
+                    BinOp::Mul => "multiply",
+                    BinOp::Div => "divide",
+                    _ => "calculate the remainder",
+                };
+                write!(f, "attempt to {} with overflow", verb)
+            }
+            Error::DivisionByZero(BinOp::Rem) => {
+                write!(f, "attempt to calculate the remainder with a divisor of zero")
+            }
+            Error::DivisionByZero(_) => write!(f, "attempt to divide by zero"),
+            Error::InvalidOperands(op, v1, v2) => write!(f, "cannot apply `{}` to {} and {}", op, v1, v2),
+            Error::InvalidCondition(v) => write!(f, "expected a boolean condition, found {}", v),
+            Error::UnboundFn(name) => write!(f, "call to undefined function `{}`", name),
+            Error::UseAfterFree(loc, _) => write!(f, "use of freed location `{}`", loc),
+            Error::Unallocated(loc) => write!(f, "use of never allocated location `{}`", loc),
+            Error::DoubleFree(loc, _) => write!(f, "double free of location `{}`", loc),
+            Error::Leaked(leaks) => {
+                let locs: Vec<String> = leaks.iter().map(|(loc, _)| format!("`{}`", loc)).collect();
+                let plural = if leaks.len() == 1 { "" } else { "s" };
+                write!(f, "{} heap allocation{} leaked at exit: {}", leaks.len(), plural, locs.join(", "))
+            }
+            Error::At(inner, span) => write!(f, "{} at {}:{}", inner, span.line, span.col),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type EvalResult<T> = Result<T, Error>;
+
+// Cells created by `Box::new`. Addresses normally only ever go up, so a
+// reference to a freed cell can never reach a later allocation. With `reuse`
+// set, the most recently freed address is handed out again instead, the way a
+// real allocator would, which lets tests show a use-after-free going wrong.
 #[derive(Clone, Debug, Default)]
-pub struct Store(pub HashMap<Location, Slot>);
+pub struct Heap {
+    pub cells: HashMap<Location, Slot>,
+    pub reuse: bool,
+    // Whether locations name the generation of their address as well, so a
+    // reference to a freed cell never reaches a later one at the same address.
+    pub tagged: bool,
+    // How many times each address has been handed out.
+    generations: Vec<usize>,
+    addresses: HashMap<Location, (usize, usize)>,
+    free: Vec<usize>,
+}
+
+// Heaps are equal when they hold the same cells and will hand out addresses
+// the same way. How many addresses they have handed out so far does not
+// matter, so a store that has been used and emptied equals a fresh one.
+impl PartialEq for Heap {
+    fn eq(&self, other: &Heap) -> bool {
+        self.cells == other.cells && self.reuse == other.reuse && self.tagged == other.tagged
+    }
+}
+
+impl Heap {
+    pub fn alloc(&mut self, value: Pvalue) -> Location {
+        let addr = match self.free.pop() {
+            Some(addr) if self.reuse => addr,
+            _ => {
+                self.generations.push(0);
+                self.generations.len() - 1
+            }
+        };
+        self.generations[addr] += 1;
+        let generation = self.generations[addr];
+        let loc = match self.tagged {
+            true => format!("loc_{}.{}", addr, generation),
+            false => format!("loc_{}", addr),
+        };
+        self.addresses.insert(loc.clone(), (addr, generation));
+        self.cells.insert(loc.clone(), Slot { value, lifetime: Lifetime::global() });
+        loc
+    }
+
+    pub fn free(&mut self, loc: &str) -> Option<Slot> {
+        let slot = self.cells.remove(loc)?;
+        if self.reuse {
+            self.free.push(self.addresses[loc].0);
+        }
+        Some(slot)
+    }
+
+    // The generation `loc` was handed out in.
+    pub fn generation(&self, loc: &str) -> Option<usize> {
+        self.addresses.get(loc).map(|(_, generation)| *generation)
+    }
+}
+
+// Variables live in `slots`, heap cells in `heap`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Store {
+    pub slots: HashMap<Location, Slot>,
+    // Variables hidden by a later `let` of the same name, as (location,
+    // hidden location) pairs, innermost last.
+    pub shadowed: Vec<(Location, Location)>,
+    // Every variable, in the order it was declared.
+    pub order: Vec<Location>,
+    pub heap: Heap,
+}
+
+// Bookkeeping for checked mode: every heap allocation with its generation
+// and site, and every location freed so far with the site that freed it.
+#[derive(Clone, Debug, Default)]
+pub struct Sanitizer {
+    pub allocations: Vec<(Location, usize, Option<Span>)>,
+    pub frees: HashMap<Location, Option<Span>>,
+}
+
+impl Sanitizer {
+    pub fn sites(&self, loc: &str) -> Sites {
+        Sites {
+            allocated: self.allocations.iter().find(|(l, ..)| l == loc).and_then(|(_, _, site)| *site),
+            freed: self.frees.get(loc).copied().flatten(),
+        }
+    }
+
+    // Turns a failed lookup or free into a report naming what became of the
+    // location.
+    fn explain(&self, err: Error) -> Error {
+        match err {
+            Error::DanglingLocation(loc) | Error::DoubleFree(loc, _) if !self.frees.contains_key(&loc) => {
+                Error::Unallocated(loc)
+            }
+            Error::DanglingLocation(loc) => Error::UseAfterFree(loc.clone(), self.sites(&loc)),
+            Error::DoubleFree(loc, _) => Error::DoubleFree(loc.clone(), self.sites(&loc)),
+            e => e,
+        }
+    }
+}
+
+// Each invocation gets its own names for locals and its own lifetimes for
+// blocks, so recursive calls never share or drop each other's slots.
+#[derive(Clone, Debug, Default)]
+struct Frame {
+    id: usize,
+    lifetimes: HashMap<Lifetime, Lifetime>,
+}
 
 #[derive(Clone, Debug, Default)]
 pub struct Context {
     pub store: Store,
+    // Every location freed so far, in the order it was freed.
+    pub drops: Vec<(Location, Lifetime)>,
+    // Present in checked mode only.
+    pub sanitizer: Option<Sanitizer>,
+    site: Option<Span>,
+    fns: HashMap<Ident, FnDef>,
+    frames: Vec<Frame>,
+    calls: usize,
+    fresh_lifetimes: usize,
 }
 
 impl Store {
-    pub fn locate<'a>(&self, lval: &'a Lval) -> &'a Location {
-        &lval.ident
+    pub fn insert(&mut self, loc: &str, value: Pvalue, lifetime: Lifetime) {
+        if !self.slots.contains_key(loc) {
+            self.order.push(loc.to_string());
+        }
+        self.slots.insert(loc.to_string(), Slot { value, lifetime });
+    }
+
+    pub fn alloc(&mut self, value: Pvalue) -> Location {
+        self.heap.alloc(value)
+    }
+
+    pub fn get(&self, loc: &str) -> Option<&Slot> {
+        self.slots.get(loc).or_else(|| self.heap.cells.get(loc))
+    }
+
+    fn get_mut(&mut self, loc: &str) -> Option<&mut Slot> {
+        match self.slots.get_mut(loc) {
+            Some(slot) => Some(slot),
+            None => self.heap.cells.get_mut(loc),
+        }
+    }
+
+    fn remove(&mut self, loc: &str) -> Option<Slot> {
+        match self.slots.remove(loc) {
+            Some(slot) => {
+                self.order.retain(|l| l != loc);
+                Some(slot)
+            }
+            None => self.heap.free(loc),
+        }
+    }
+
+    // In declaration order; `drop` works from the back, so the most recently
+    // declared location is freed first.
+    pub fn locs_by_lifetime(&self, l: Lifetime) -> Vec<Pvalue> {
+        self.order.iter()
+            .filter(|loc| self.slots.get(*loc).is_some_and(|slot| slot.lifetime == l))
+            .map(|loc| Some(Value::Ref(loc.clone(), true)))
+            .collect()
+    }
+
+    fn rename(&mut self, from: &str, to: &str) {
+        if let Some(slot) = self.slots.remove(from) {
+            self.slots.insert(to.to_string(), slot);
+        }
+        for loc in self.order.iter_mut().filter(|loc| *loc == from) {
+            *loc = to.to_string();
+        }
+        for slot in self.slots.values_mut().chain(self.heap.cells.values_mut()) {
+            match &mut slot.value {
+                Some(Value::Ref(loc, false)) if loc == from => *loc = to.to_string(),
+                _ => {}
+            }
+        }
+    }
+
+    // Moves an existing variable out of the way of a new one with the same
+    // name, keeping it alive and references to it valid.
+    pub fn shadow(&mut self, loc: &str) -> Option<Location> {
+        if !self.slots.contains_key(loc) {
+            return None;
+        }
+        let depth = self.shadowed.iter().filter(|(name, _)| name == loc).count() + 1;
+        let hidden = format!("{}~{}", loc, depth);
+        self.rename(loc, &hidden);
+        self.shadowed.push((loc.to_string(), hidden.clone()));
+        Some(hidden)
+    }
+
+    // Brings back the variables whose shadowing `let` has gone out of scope.
+    pub fn unshadow(&mut self) {
+        for i in (0..self.shadowed.len()).rev() {
+            let (loc, hidden) = self.shadowed[i].clone();
+            if !self.slots.contains_key(&loc) {
+                self.shadowed.remove(i);
+                self.rename(&hidden, &loc);
+            }
+        }
+    }
+
+    // Follows `lval.derefs` references starting from the variable itself.
+    pub fn try_locate(&self, lval: &Lval) -> EvalResult<Location> {
+        let mut loc = lval.ident.clone();
+        for _ in 0..lval.derefs {
+            loc = match self.get(&loc) {
+                Some(Slot { value: Some(Value::Ref(next, _)), .. }) => next.clone(),
+                Some(Slot { value: None, .. }) => return Err(Error::UseAfterMove(lval.clone())),
+                Some(_) => return Err(Error::InvalidDeref(lval.clone())),
+                None => return Err(Error::DanglingLocation(loc)),
+            };
+        }
+        Ok(loc)
+    }
+
+    pub fn try_read(&self, lval: &Lval) -> EvalResult<&Slot> {
+        let loc = self.try_locate(lval)?;
+        self.get(&loc).ok_or(Error::DanglingLocation(loc))
+    }
+
+    pub fn try_write(&mut self, lval: &Lval, new_val: Pvalue) -> EvalResult<Pvalue> {
+        let loc = self.try_locate(lval)?;
+        let slot = self.get_mut(&loc).ok_or(Error::DanglingLocation(loc))?;
+        Ok(std::mem::replace(&mut slot.value, new_val))
+    }
+
+    pub fn locate(&self, lval: &Lval) -> Location {
+        self.try_locate(lval).expect("locate: invalid dereference")
     }
 
     pub fn read(&self, lval: &Lval) -> &Slot {
-        let loc = self.locate(lval);
-        self.0.get(loc).expect("read: location not found")
+        self.try_read(lval).expect("read: location not found")
     }
 
     pub fn write(&mut self, lval: &Lval, new_val: Pvalue) -> Pvalue {
-        let loc = self.locate(lval).clone();
-        let slot = self.0.get(&loc).expect("write: location not found");
-        let old_val = slot.value.clone();
-        let lifetime = slot.lifetime.clone();
+        self.try_write(lval, new_val).expect("write: location not found")
+    }
 
-        self.0.insert(loc, Slot {
-            value: new_val,
-            lifetime,
-        });
+    // Frees every owned location in `to_remove`, last first, along with
+    // whatever those locations own in turn. Returns the freed locations in
+    // the order they went.
+    pub fn drop(&mut self, to_remove: Vec<Pvalue>) -> Vec<(Location, Lifetime)> {
+        let mut freed = vec![];
+        let _ = self.free(to_remove, &mut freed, false);
+        freed
+    }
 
-        old_val
+    // Like `drop`, but an owned location that is already gone is a double
+    // free. Whatever was freed before that is still added to `freed`.
+    pub fn try_drop(&mut self, to_remove: Vec<Pvalue>, freed: &mut Vec<(Location, Lifetime)>) -> EvalResult<()> {
+        self.free(to_remove, freed, true)
     }
 
-    pub fn drop(&mut self, to_remove: Vec<Pvalue>) {
-        for val in to_remove {
+    fn free(&mut self, to_remove: Vec<Pvalue>, freed: &mut Vec<(Location, Lifetime)>, checked: bool) -> EvalResult<()> {
+        let mut pending = to_remove;
+        while let Some(val) = pending.pop() {
             if let Some(Value::Ref(loc, true)) = val {
-                self.0.remove(&loc);
+                match self.remove(&loc) {
+                    Some(slot) => {
+                        freed.push((loc, slot.lifetime));
+                        pending.push(slot.value);
+                    }
+                    None if checked => return Err(Error::DoubleFree(loc, Sites::default())),
+                    None => {}
+                }
             }
         }
+        Ok(())
     }
 }
 
@@ -66,85 +395,262 @@ impl Lifetime {
     }
 }
 
+// The place `lval` names in the program, without the frame suffix `local`
+// gives it.
+fn unlocal(lval: &Lval) -> Lval {
+    let ident = lval.ident.split('#').next().unwrap_or(&lval.ident);
+    Lval::new(ident, lval.derefs)
+}
+
+fn binop(op: BinOp, v1: Value, v2: Value) -> EvalResult<Value> {
+    let (a, b) = match (&v1, &v2) {
+        (Value::Int(a), Value::Int(b)) => (*a, *b),
+        _ => return Err(Error::InvalidOperands(op, v1, v2)),
+    };
+    if b == 0 && matches!(op, BinOp::Div | BinOp::Rem) {
+        return Err(Error::DivisionByZero(op));
+    }
+    let result = match op {
+        BinOp::Add => a.checked_add(b),
+        BinOp::Sub => a.checked_sub(b),
+        BinOp::Mul => a.checked_mul(b),
+        BinOp::Div => a.checked_div(b),
+        BinOp::Rem => a.checked_rem(b),
+        BinOp::Eq => return Ok(Value::Bool(a == b)),
+        BinOp::Ne => return Ok(Value::Bool(a != b)),
+        BinOp::Lt => return Ok(Value::Bool(a < b)),
+        BinOp::Le => return Ok(Value::Bool(a <= b)),
+        BinOp::Gt => return Ok(Value::Bool(a > b)),
+        BinOp::Ge => return Ok(Value::Bool(a >= b)),
+    };
+    result.map(Value::Int).ok_or(Error::Overflow(op))
+}
+
 impl Context {
-    pub fn eval_expr(&mut self, expr: &Expr, l: Lifetime) -> Value {
+    // A context whose heap hands out freed addresses again.
+    pub fn reusing_addresses() -> Context {
+        let mut ctx = Context::default();
+        ctx.store.heap.reuse = true;
+        ctx
+    }
+
+    // A context that tags heap cells with their generation and reports
+    // memory errors itself rather than trusting the type checker.
+    pub fn checked() -> Context {
+        let mut ctx = Context::default();
+        ctx.store.heap.tagged = true;
+        ctx.sanitizer = Some(Sanitizer::default());
+        ctx
+    }
+
+    fn drop(&mut self, to_remove: Vec<Pvalue>) -> EvalResult<()> {
+        let Some(sanitizer) = &mut self.sanitizer else {
+            let freed = self.store.drop(to_remove);
+            self.drops.extend(freed);
+            return Ok(());
+        };
+        let mut freed = vec![];
+        let result = self.store.try_drop(to_remove, &mut freed);
+        for (loc, _) in &freed {
+            sanitizer.frees.insert(loc.clone(), self.site);
+        }
+        self.drops.extend(freed);
+        result.map_err(|e| sanitizer.explain(e))
+    }
+
+    // Places in errors are named as the program wrote them, not by the
+    // names their frame gives them.
+    fn explain(&self, err: Error) -> Error {
+        let err = match err {
+            Error::UseAfterMove(lval) => Error::UseAfterMove(unlocal(&lval)),
+            Error::InvalidDeref(lval) => Error::InvalidDeref(unlocal(&lval)),
+            e => e,
+        };
+        match &self.sanitizer {
+            Some(sanitizer) => sanitizer.explain(err),
+            None => err,
+        }
+    }
+
+    // Heap cells still allocated once the program is done.
+    fn leaks(&self) -> Vec<(Location, Sites)> {
+        let Some(sanitizer) = &self.sanitizer else {
+            return vec![];
+        };
+        sanitizer.allocations.iter()
+            .filter(|(loc, ..)| self.store.heap.cells.contains_key(loc))
+            .map(|(loc, ..)| (loc.clone(), sanitizer.sites(loc)))
+            .collect()
+    }
+
+    pub fn eval_program(&mut self, program: &Program) -> EvalResult<Value> {
+        for f in &program.fns {
+            self.fns.insert(f.name.clone(), f.clone());
+        }
+        let main = program.get("main").ok_or(Error::UnboundFn(String::from("main")))?;
+        let result = self.eval_expr(&main.body, Lifetime::global())?;
+        let leaks = self.leaks();
+        if !leaks.is_empty() {
+            return Err(Error::Leaked(leaks));
+        }
+        Ok(result)
+    }
+
+    fn local(&self, lval: &Lval) -> Lval {
+        match self.frames.last() {
+            Some(frame) => Lval::new(&format!("{}#{}", lval.ident, frame.id), lval.derefs),
+            None => lval.clone(),
+        }
+    }
+
+    // Frame lifetimes count down from the top so they never meet the ones
+    // handed out by the parser.
+    fn local_lifetime(&mut self, l: &Lifetime) -> Lifetime {
+        let Some(frame) = self.frames.last_mut() else {
+            return l.clone();
+        };
+        let fresh = &mut self.fresh_lifetimes;
+        frame.lifetimes.entry(l.clone()).or_insert_with(|| {
+            *fresh += 1;
+            Lifetime(usize::MAX - *fresh)
+        }).clone()
+    }
+
+    pub fn eval_expr(&mut self, expr: &Expr, l: Lifetime) -> EvalResult<Value> {
         match expr {
-            Expr::Unit => Value::Unit,
-            Expr::Int(n) => Value::Int(*n),
+            Expr::Unit => Ok(Value::Unit),
+            Expr::Int(n) => Ok(Value::Int(*n)),
+            Expr::Bool(b) => Ok(Value::Bool(*b)),
 
             Expr::Lval(lval, copyable) => {
-                let slot = self.store.read(lval);
-                let v = slot.value.clone().unwrap();
+                let local = &self.local(lval);
+                let slot = self.store.try_read(local).map_err(|e| self.explain(e))?;
+                let v = slot.value.clone().ok_or(Error::UseAfterMove(lval.clone()))?;
 
                 if !*copyable {
-                    self.store.write(lval, None);
+                    self.store.try_write(local, None).map_err(|e| self.explain(e))?;
                 }
 
-                v
+                Ok(v)
             }
 
             Expr::Box(e) => {
-                let v = self.eval_expr(e, l.clone());
-                let fresh_id = format!("loc_{}", self.store.0.len());
-                self.store.0.insert(fresh_id.clone(), Slot {
-                    value: Some(v),
-                    lifetime: Lifetime::global(),
-                });
-                Value::Ref(fresh_id, true)
+                let v = self.eval_expr(e, l.clone())?;
+                let loc = self.store.alloc(Some(v));
+                if let Some(sanitizer) = &mut self.sanitizer {
+                    let generation = self.store.heap.generation(&loc).unwrap_or_default();
+                    sanitizer.allocations.push((loc.clone(), generation, self.site));
+                }
+                Ok(Value::Ref(loc, true))
             }
 
             Expr::Borrow(lval, _is_mut) => {
-                let loc = self.store.locate(lval).clone();
-                Value::Ref(loc, false)
+                let loc = self.store.try_locate(&self.local(lval)).map_err(|e| self.explain(e))?;
+                Ok(Value::Ref(loc, false))
             }
 
             Expr::Block(stmts, final_expr, block_lifetime) => {
+                let block_lifetime = &self.local_lifetime(block_lifetime);
                 for stmt in stmts {
-                    self.eval_stmt(stmt, block_lifetime.clone());
+                    self.eval_stmt(stmt, block_lifetime.clone())?;
                 }
 
-                let result = self.eval_expr(final_expr, block_lifetime.clone());
+                let result = self.eval_expr(final_expr, block_lifetime.clone())?;
 
-                let to_drop: Vec<_> = self.store.0
-                    .iter()
-                    .filter(|(_, slot)| slot.lifetime == *block_lifetime)
-                    .map(|(_, slot)| slot.value.clone())
-                    .collect();
+                let to_drop = self.store.locs_by_lifetime(block_lifetime.clone());
+                self.drop(to_drop)?;
+                self.store.unshadow();
 
-                self.store.drop(to_drop);
-
-                result
+                Ok(result)
             }
             Expr::AssertEq(left, right) => {
-                let v1 = self.eval_expr(left, l.clone());
-                let v2 = self.eval_expr(right, l);
-                match (v1, v2) {
-                    (Value::Int(a), Value::Int(b)) => assert_eq!(a, b),
-                    _ => panic!("assert_eq! only supports integers"),
+                let v1 = self.eval_expr(left, l.clone())?;
+                let v2 = self.eval_expr(right, l)?;
+                if v1 != v2 {
+                    return Err(Error::AssertionFailed(v1, v2));
+                }
+                Ok(Value::Unit)
+            }
+            Expr::BinOp(op, left, right) => {
+                let v1 = self.eval_expr(left, l.clone())?;
+                let v2 = self.eval_expr(right, l)?;
+                binop(*op, v1, v2)
+            }
+            Expr::If(cond, then, otherwise) => match self.eval_expr(cond, l.clone())? {
+                Value::Bool(true) => self.eval_expr(then, l),
+                Value::Bool(false) => self.eval_expr(otherwise, l),
+                v => Err(Error::InvalidCondition(v)),
+            },
+            Expr::While(cond, body) => {
+                loop {
+                    match self.eval_expr(cond, l.clone())? {
+                        Value::Bool(true) => self.eval_expr(body, l.clone())?,
+                        Value::Bool(false) => return Ok(Value::Unit),
+                        v => return Err(Error::InvalidCondition(v)),
+                    };
+                }
+            }
+            Expr::Call(name, args) => {
+                let def = self.fns.get(name).cloned().ok_or(Error::UnboundFn(name.clone()))?;
+                let mut vals = vec![];
+                for arg in args {
+                    vals.push(self.eval_expr(arg, l.clone())?);
+                }
+
+                self.calls += 1;
+                self.frames.push(Frame { id: self.calls, ..Frame::default() });
+                let body_lifetime = self.local_lifetime(&def.lifetime());
+                for ((param, _), val) in def.params.iter().zip(vals) {
+                    let loc = self.local(&Lval::var(param)).ident;
+                    self.store.insert(&loc, Some(val), body_lifetime.clone());
                 }
-                Value::Unit
+                let result = self.eval_expr(&def.body, l);
+                self.frames.pop();
+                result
+            }
+            Expr::At(inner, span) => {
+                let outer = self.site.replace(*span);
+                let result = self.eval_expr(inner, l).map_err(|e| e.at(*span));
+                self.site = outer;
+                result
             }
         }
     }
 
-    pub fn eval_stmt(&mut self, stmt: &Stmt, l: Lifetime) {
+    pub fn eval_stmt(&mut self, stmt: &Stmt, l: Lifetime) -> EvalResult<()> {
         match stmt {
             Stmt::Assign(lval, expr) => {
-                let val = self.eval_expr(expr, l.clone());
-                self.store.write(lval, Some(val));
+                let val = self.eval_expr(expr, l.clone())?;
+                let old = self.store.try_write(&self.local(lval), Some(val)).map_err(|e| self.explain(e))?;
+                self.drop(vec![old])?;
             }
 
-            Stmt::LetMut(ident, expr) => {
-                let val = self.eval_expr(expr, l.clone());
-                self.store.0.insert(ident.clone(), Slot {
-                    value: Some(val),
-                    lifetime: l,
-                });
+            Stmt::Let(ident, expr) | Stmt::LetMut(ident, expr) => {
+                let mut val = self.eval_expr(expr, l.clone())?;
+                let loc = self.local(&Lval::var(ident)).ident;
+                if let (Some(hidden), Value::Ref(target, false)) = (self.store.shadow(&loc), &mut val) {
+                    if *target == loc {
+                        *target = hidden;
+                    }
+                }
+                self.store.insert(&loc, Some(val), l);
             }
 
+            // A value nothing takes ownership of is dropped straight away.
             Stmt::Expr(expr) => {
-                self.eval_expr(expr, l);
+                let val = self.eval_expr(expr, l)?;
+                self.drop(vec![Some(val)])?;
+            }
+
+            Stmt::At(inner, span) => {
+                let outer = self.site.replace(*span);
+                let result = self.eval_stmt(inner, l).map_err(|e| e.at(*span));
+                self.site = outer;
+                result?;
             }
         }
+        Ok(())
     }
 }
+
+pub use Error as RuntimeError;
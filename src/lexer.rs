@@ -1,4 +1,4 @@
-use std::str::Lines;
+use crate::utils::Span;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
@@ -11,113 +11,197 @@ pub enum Token {
     Star,
     Comma,
     Semicolon,
+    Colon,
+    Arrow,
+    Plus,
+    Minus,
+    Slash,
+    Percent,
+    EqEq,
+    Ne,
+    Le,
+    Ge,
+    Lt,
+    Gt,
     Fn,
     Let,
     Mut,
     Box,
     AssertEq,
+    True,
+    False,
+    If,
+    Else,
+    While,
     Int(i32),
     Var(String),
+    Lifetime(String),
 }
 
-const LEXEMES: [(&str, Token); 14] = [
+const LEXEMES: [(&str, Token); 31] = [
     ("(", Token::Lparen),
     (")", Token::Rparen),
     ("{", Token::Lbracket),
     ("}", Token::Rbracket),
+    ("==", Token::EqEq),
+    ("!=", Token::Ne),
+    ("<=", Token::Le),
+    (">=", Token::Ge),
+    ("<", Token::Lt),
+    (">", Token::Gt),
     ("=", Token::Eq),
+    ("+", Token::Plus),
+    ("->", Token::Arrow),
+    ("-", Token::Minus),
+    ("/", Token::Slash),
+    ("%", Token::Percent),
     ("&", Token::Ampersand),
     ("*", Token::Star),
     (",", Token::Comma),
     (";", Token::Semicolon),
+    (":", Token::Colon),
     ("fn", Token::Fn),
     ("let", Token::Let),
     ("mut", Token::Mut),
     ("Box::new", Token::Box),
     ("assert_eq!", Token::AssertEq),
+    ("true", Token::True),
+    ("false", Token::False),
+    ("if", Token::If),
+    ("else", Token::Else),
+    ("while", Token::While),
     ];
 
-#[derive(Debug)]
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Int(n) => write!(f, "{}", n),
+            Token::Var(s) => write!(f, "{}", s),
+            Token::Lifetime(s) => write!(f, "'{}", s),
+            tok => {
+                let (lexeme, _) = LEXEMES.iter().find(|(_, t)| t == tok).unwrap();
+                write!(f, "{}", lexeme)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Error {
-    Unknown(usize, usize),
+    Unknown(Span),
+    IntOverflow(Span),
 }
 
-type LexResult = Result<Token, Error>;
+impl Error {
+    pub fn span(&self) -> Span {
+        match self {
+            Error::Unknown(span) | Error::IntOverflow(span) => *span,
+        }
+    }
+}
+
+type LexResult = Result<(Token, Span), Error>;
 
 pub struct Lexer<'a> {
-    contents: Lines<'a>,
-    curr_line_num: usize,
-    curr_col_num: usize,
-    pub curr_line: &'a str,
+    input: &'a str,
+    pos: usize,
+    line: usize,
+    line_start: usize,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str) -> Self {
-        let mut lines = input.lines();
-        let first_line = lines.next().unwrap_or("");
         Lexer {
-            contents: lines,
-            curr_line_num: 1,
-            curr_col_num: 0,
-            curr_line: first_line,
+            input,
+            pos: 0,
+            line: 1,
+            line_start: 0,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn span(&self, len: usize) -> Span {
+        Span {
+            start: self.pos,
+            end: self.pos + len,
+            line: self.line,
+            col: self.pos - self.line_start + 1,
         }
     }
 
     fn unknown(&self) -> Error {
-        Error::Unknown(self.curr_line_num, self.curr_col_num)
+        let len = self.rest().chars().next().map_or(1, char::len_utf8);
+        Error::Unknown(self.span(len))
     }
 
-    fn consume(&mut self, n: usize) {
-        self.curr_col_num += n;
-        self.curr_line = &self.curr_line[n..];
-        while self.curr_line.trim().is_empty() {
-            if let Some(next_line) = self.contents.next() {
-                self.curr_line_num += 1;
-                self.curr_col_num = 0;
-                self.curr_line = next_line;
-            } else {
+    fn consume(&mut self, n: usize, token: Token) -> LexResult {
+        let span = self.span(n);
+        self.pos += n;
+        Ok((token, span))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.rest().chars().next() {
+            if !c.is_whitespace() {
                 break;
             }
+            self.pos += c.len_utf8();
+            if c == '\n' {
+                self.line += 1;
+                self.line_start = self.pos;
+            }
         }
-        self.curr_line = self.curr_line.trim_start();
     }
 
     fn symbol_or_keyword(&mut self) -> Option<LexResult> {
         for (lexeme, token) in LEXEMES.iter() {
-            if self.curr_line.starts_with(lexeme) {
-                self.consume(lexeme.len());
-                return Some(Ok(token.clone()));
+            let rest = self.rest();
+            // Keywords only match whole words, so `iffy` stays a variable.
+            let word = lexeme.ends_with(|c: char| c.is_ascii_alphanumeric())
+                && rest.get(lexeme.len()..).is_some_and(|after| after.starts_with(|c: char| c.is_ascii_alphanumeric()));
+            if rest.starts_with(lexeme) && !word {
+                return Some(self.consume(lexeme.len(), token.clone()));
             }
         }
         None
     }
 
     fn variable(&mut self) -> Option<LexResult> {
-        let chars: Vec<char> = self.curr_line.chars().collect();
-        if chars.is_empty() || !chars[0].is_ascii_alphabetic() {
+        let rest = self.rest();
+        if !rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
             return None;
         }
-        let mut end = 1;
-        while end < chars.len() && chars[end].is_ascii_alphanumeric() {
-            end += 1;
+        let end = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+        Some(self.consume(end, Token::Var(rest[..end].to_string())))
+    }
+
+    // Lifetime names are a quote followed by an identifier, e.g. `'a`.
+    fn lifetime(&mut self) -> Option<LexResult> {
+        let name = self.rest().strip_prefix('\'')?;
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return None;
         }
-        let ident = &self.curr_line[..end];
-        self.consume(end);
-        Some(Ok(Token::Var(ident.to_string())))
+        let end = name.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(name.len());
+        Some(self.consume(end + 1, Token::Lifetime(name[..end].to_string())))
     }
 
     fn int(&mut self) -> Option<LexResult> {
-        let chars: Vec<char> = self.curr_line.chars().collect();
-        let mut end = 0;
-        while end < chars.len() && chars[end].is_ascii_digit() {
-            end += 1;
-        }
+        let rest = self.rest();
+        let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
         if end == 0 {
             return None;
         }
-        let number: i32 = self.curr_line[..end].parse().unwrap();
-        self.consume(end);
-        Some(Ok(Token::Int(number)))
+        match rest[..end].parse() {
+            Ok(number) => Some(self.consume(end, Token::Int(number))),
+            Err(_) => {
+                let err = Error::IntOverflow(self.span(end));
+                self.pos += end;
+                Some(Err(err))
+            }
+        }
     }
 }
 
@@ -125,16 +209,9 @@ impl<'a> Iterator for Lexer<'a> {
     type Item = LexResult;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.curr_line = self.curr_line.trim_start();
-        if self.curr_line.is_empty() {
-            if let Some(next_line) = self.contents.next() {
-                self.curr_line_num += 1;
-                self.curr_col_num = 0;
-                self.curr_line = next_line;
-                return self.next();
-            } else {
-                return None;
-            }
+        self.skip_whitespace();
+        if self.rest().is_empty() {
+            return None;
         }
 
         if let Some(tok) = self.symbol_or_keyword() {
@@ -149,6 +226,12 @@ impl<'a> Iterator for Lexer<'a> {
             return Some(tok);
         }
 
-        Some(Err(self.unknown()))
+        if let Some(tok) = self.lifetime() {
+            return Some(tok);
+        }
+
+        let err = self.unknown();
+        self.pos = self.input.len();
+        Some(Err(err))
     }
 }
@@ -1,8 +1,10 @@
 pub mod utils;   
 pub mod eval;    
 pub mod types;   
+pub mod liveness;
 pub mod lexer;   
 pub mod parser;  
+pub mod diagnostics;
 
 #[cfg(test)]
 mod part_1_tests; 
@@ -10,3 +12,7 @@ mod part_1_tests;
 mod part_2_tests; 
 #[cfg(test)]
 mod types_tests;
+#[cfg(test)]
+mod parser_tests;
+#[cfg(test)]
+mod diagnostics_tests;
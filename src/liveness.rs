@@ -0,0 +1,59 @@
+use std::collections::HashSet;
+use crate::utils::*;
+
+// Adds every variable `expr` reads, writes or borrows to `out`.
+pub fn uses(expr: &Expr, out: &mut HashSet<Ident>) {
+    match expr {
+        Expr::Unit | Expr::Int(_) | Expr::Bool(_) => {}
+        Expr::Lval(lval, _) | Expr::Borrow(lval, _) => {
+            out.insert(lval.ident.clone());
+        }
+        Expr::Box(inner) | Expr::At(inner, _) => uses(inner, out),
+        Expr::Block(stmts, tail, _) => {
+            for stmt in stmts {
+                stmt_uses(stmt, out);
+            }
+            uses(tail, out);
+        }
+        Expr::AssertEq(left, right) | Expr::BinOp(_, left, right) | Expr::While(left, right) => {
+            uses(left, out);
+            uses(right, out);
+        }
+        Expr::If(cond, then, otherwise) => {
+            uses(cond, out);
+            uses(then, out);
+            uses(otherwise, out);
+        }
+        Expr::Call(_, args) => {
+            for arg in args {
+                uses(arg, out);
+            }
+        }
+    }
+}
+
+// Assigning to a variable counts as a use, which keeps a reference held by
+// it alive until it is overwritten.
+pub fn stmt_uses(stmt: &Stmt, out: &mut HashSet<Ident>) {
+    match stmt {
+        Stmt::Assign(lval, expr) => {
+            out.insert(lval.ident.clone());
+            uses(expr, out);
+        }
+        Stmt::Let(_, expr) | Stmt::LetMut(_, expr) | Stmt::Expr(expr) => uses(expr, out),
+        Stmt::At(inner, _) => stmt_uses(inner, out),
+    }
+}
+
+// For each statement of a block, the variables still needed once it has run:
+// those mentioned by any later statement or by the tail.
+pub fn live_after(stmts: &[Stmt], tail: &Expr) -> Vec<HashSet<Ident>> {
+    let mut live = HashSet::new();
+    uses(tail, &mut live);
+    let mut result = vec![HashSet::new(); stmts.len()];
+    for (i, stmt) in stmts.iter().enumerate().rev() {
+        result[i] = live.clone();
+        stmt_uses(stmt, &mut live);
+    }
+    result
+}
@@ -1,12 +1,23 @@
 use std::iter::Peekable;
 use crate::lexer::{Lexer, Token};
+use crate::types::{Region, Type};
 use crate::utils::*;
 
 #[derive(Debug)]
 pub enum Error {
-    EndOfFile,
+    EndOfFile(Span),
     Lexer(crate::lexer::Error),
-    Unexpected(Token),
+    Unexpected(Token, Span),
+}
+
+impl Error {
+    pub fn span(&self) -> Span {
+        match self {
+            Error::EndOfFile(span) => *span,
+            Error::Lexer(err) => err.span(),
+            Error::Unexpected(_, span) => *span,
+        }
+    }
 }
 
 type ParseResult<T> = Result<T, Error>;
@@ -14,6 +25,7 @@ type ParseResult<T> = Result<T, Error>;
 pub struct Parser<'a> {
     pub lexer: Peekable<Lexer<'a>>,
     pub fresh: usize,
+    last: Span,
 }
 
 impl<'a> Parser<'a> {
@@ -21,14 +33,23 @@ impl<'a> Parser<'a> {
         Parser {
             lexer: Lexer::new(input).peekable(),
             fresh: 1,
+            last: Span::default(),
         }
     }
 
+    fn end_of_file(&self) -> Error {
+        let end = Span { start: self.last.end, col: self.last.col + self.last.end - self.last.start, ..self.last };
+        Error::EndOfFile(end)
+    }
+
     fn next_token(&mut self) -> ParseResult<Token> {
         match self.lexer.next() {
-            Some(Ok(tok)) => Ok(tok),
+            Some(Ok((tok, span))) => {
+                self.last = span;
+                Ok(tok)
+            }
             Some(Err(err)) => Err(Error::Lexer(err)),
-            None => Err(Error::EndOfFile),
+            None => Err(self.end_of_file()),
         }
     }
 
@@ -37,14 +58,33 @@ impl<'a> Parser<'a> {
         if tok == expected {
             Ok(tok)
         } else {
-            Err(Error::Unexpected(tok))
+            Err(Error::Unexpected(tok, self.last))
         }
     }
 
     fn peek_token(&mut self) -> ParseResult<&Token> {
+        if self.lexer.peek().is_none() {
+            return Err(self.end_of_file());
+        }
+        match self.lexer.peek() {
+            Some(Ok((tok, _))) => Ok(tok),
+            Some(Err(err)) => Err(Error::Lexer(err.clone())),
+            None => unreachable!(),
+        }
+    }
+
+    fn peek_span(&mut self) -> ParseResult<Span> {
+        self.peek_token()?;
         match self.lexer.peek() {
-            Some(Ok(tok)) => Ok(tok),
-            Some(Err(_)) | None => Err(Error::EndOfFile),
+            Some(Ok((_, span))) => Ok(*span),
+            _ => unreachable!(),
+        }
+    }
+
+    fn unexpected(&mut self) -> Error {
+        match self.next_token() {
+            Ok(tok) => Error::Unexpected(tok, self.last),
+            Err(err) => err,
         }
     }
 
@@ -56,27 +96,85 @@ impl<'a> Parser<'a> {
         }
         let name = match self.next_token()? {
             Token::Var(s) => s,
-            t => return Err(Error::Unexpected(t)),
+            t => return Err(Error::Unexpected(t, self.last)),
         };
         Ok(Lval { ident: name, derefs })
     }
 
+    // Binding power of an infix operator token, following Rust: comparisons
+    // bind loosest, then additive, then multiplicative operators.
+    fn binop(tok: &Token) -> Option<(BinOp, u8)> {
+        let op = match tok {
+            Token::EqEq => (BinOp::Eq, 1),
+            Token::Ne => (BinOp::Ne, 1),
+            Token::Lt => (BinOp::Lt, 1),
+            Token::Le => (BinOp::Le, 1),
+            Token::Gt => (BinOp::Gt, 1),
+            Token::Ge => (BinOp::Ge, 1),
+            Token::Plus => (BinOp::Add, 2),
+            Token::Minus => (BinOp::Sub, 2),
+            Token::Star => (BinOp::Mul, 3),
+            Token::Slash => (BinOp::Div, 3),
+            Token::Percent => (BinOp::Rem, 3),
+            _ => return None,
+        };
+        Some(op)
+    }
+
     fn parse_expr(&mut self) -> ParseResult<Expr> {
-        match self.peek_token()? {
+        self.parse_binary(0)
+    }
+
+    // Precedence climbing over left-associative operators. Comparisons do not
+    // associate, so `a < b < c` is rejected as in Rust.
+    fn parse_binary(&mut self, min_prec: u8) -> ParseResult<Expr> {
+        let start = self.peek_span()?;
+        let mut left = self.parse_primary()?;
+        let mut compared = false;
+        while let Some((op, prec)) = self.peek_token().ok().and_then(Self::binop) {
+            if prec <= min_prec {
+                break;
+            }
+            if op.is_comparison() && compared {
+                return Err(self.unexpected());
+            }
+            compared = op.is_comparison();
+            self.next_token()?;
+            let right = self.parse_binary(prec)?;
+            left = Expr::At(Box::new(Expr::BinOp(op, Box::new(left), Box::new(right))), start.to(self.last));
+        }
+        Ok(left)
+    }
+
+    fn parse_primary(&mut self) -> ParseResult<Expr> {
+        let start = self.peek_span()?;
+        let e = match self.peek_token()? {
             Token::Int(_) => match self.next_token()? {
-                Token::Int(n) => Ok(Expr::Int(n)),
+                Token::Int(n) => Expr::Int(n),
                 _ => unreachable!(),
             },
+            Token::True | Token::False => Expr::Bool(self.next_token()? == Token::True),
+            Token::If => self.parse_if()?,
+            Token::While => {
+                self.next_token()?;
+                let cond = self.parse_expr()?;
+                let body = self.parse_spanned_block()?;
+                Expr::While(Box::new(cond), Box::new(body))
+            }
             Token::Var(_) | Token::Star => {
                 let lval = self.parse_lval()?;
-                Ok(Expr::Lval(lval, false))
+                if lval.derefs == 0 && matches!(self.peek_token(), Ok(Token::Lparen)) {
+                    Expr::Call(lval.ident, self.parse_args()?)
+                } else {
+                    Expr::Lval(lval, false)
+                }
             }
             Token::Box => {
                 self.next_token()?;
                 self.next_token_match(Token::Lparen)?;
                 let inner = self.parse_expr()?;
                 self.next_token_match(Token::Rparen)?;
-                Ok(Expr::Box(Box::new(inner)))
+                Expr::Box(Box::new(inner))
             }
             Token::Ampersand => {
                 self.next_token()?;
@@ -85,9 +183,20 @@ impl<'a> Parser<'a> {
                     self.next_token()?;
                 }
                 let lval = self.parse_lval()?;
-                Ok(Expr::Borrow(lval, is_mut))
+                Expr::Borrow(lval, is_mut)
+            }
+            Token::Lbracket => self.parse_block()?,
+            Token::Lparen => {
+                self.next_token()?;
+                if matches!(self.peek_token()?, Token::Rparen) {
+                    self.next_token()?;
+                    Expr::Unit
+                } else {
+                    let inner = self.parse_expr()?;
+                    self.next_token_match(Token::Rparen)?;
+                    return Ok(inner);
+                }
             }
-            Token::Lbracket => self.parse_block(),
             Token::AssertEq => {
                 self.next_token()?;
                 self.next_token_match(Token::Lparen)?;
@@ -95,33 +204,72 @@ impl<'a> Parser<'a> {
                 self.next_token_match(Token::Comma)?;
                 let right = self.parse_expr()?;
                 self.next_token_match(Token::Rparen)?;
-                Ok(Expr::AssertEq(Box::new(left), Box::new(right)))
+                Expr::AssertEq(Box::new(left), Box::new(right))
             }
-            t => Err(Error::Unexpected(t.clone())),
-        }
+            _ => return Err(self.unexpected()),
+        };
+        Ok(Expr::At(Box::new(e), start.to(self.last)))
     }
 
     fn parse_stmt(&mut self) -> ParseResult<Stmt> {
-        match self.peek_token()? {
+        let start = self.peek_span()?;
+        let stmt = match self.peek_token()? {
             Token::Let => {
                 self.next_token()?;
-                self.next_token_match(Token::Mut)?;
-                let ident = match self.next_token()? {
-                    Token::Var(s) => s,
-                    t => return Err(Error::Unexpected(t)),
-                };
+                let mutable = matches!(self.peek_token()?, Token::Mut);
+                if mutable {
+                    self.next_token()?;
+                }
+                let ident = self.parse_ident()?;
                 self.next_token_match(Token::Eq)?;
                 let e = self.parse_expr()?;
-                Ok(Stmt::LetMut(ident, e))
+                if mutable {
+                    Stmt::LetMut(ident, e)
+                } else {
+                    Stmt::Let(ident, e)
+                }
             }
-            Token::Star | Token::Var(_) => {
-                let lval = self.parse_lval()?;
-                self.next_token_match(Token::Eq)?;
+            Token::Lbracket | Token::If | Token::While => Stmt::Expr(self.parse_primary()?),
+            _ => {
                 let e = self.parse_expr()?;
-                Ok(Stmt::Assign(lval, e))
+                match (e.unspanned(), self.peek_token()) {
+                    (Expr::Lval(lval, _), Ok(Token::Eq)) => {
+                        let lval = lval.clone();
+                        self.next_token()?;
+                        Stmt::Assign(lval, self.parse_expr()?)
+                    }
+                    _ => Stmt::Expr(e),
+                }
             }
-            _ => Ok(Stmt::Expr(self.parse_expr()?)),
-        }
+        };
+        Ok(Stmt::At(Box::new(stmt), start.to(self.last)))
+    }
+
+    // `if c { .. } else { .. }`; a missing `else` is an empty block and
+    // `else if` nests another conditional as the else branch.
+    fn parse_if(&mut self) -> ParseResult<Expr> {
+        self.next_token_match(Token::If)?;
+        let cond = self.parse_expr()?;
+        let then = self.parse_spanned_block()?;
+        let otherwise = if matches!(self.peek_token(), Ok(Token::Else)) {
+            self.next_token()?;
+            if matches!(self.peek_token()?, Token::If) {
+                self.parse_primary()?
+            } else {
+                self.parse_spanned_block()?
+            }
+        } else {
+            let l = Lifetime(self.fresh);
+            self.fresh += 1;
+            Expr::Block(vec![], Box::new(Expr::Unit), l)
+        };
+        Ok(Expr::If(Box::new(cond), Box::new(then), Box::new(otherwise)))
+    }
+
+    fn parse_spanned_block(&mut self) -> ParseResult<Expr> {
+        let start = self.peek_span()?;
+        let block = self.parse_block()?;
+        Ok(Expr::At(Box::new(block), start.to(self.last)))
     }
 
     fn parse_block(&mut self) -> ParseResult<Expr> {
@@ -129,13 +277,122 @@ impl<'a> Parser<'a> {
         let l = Lifetime(self.fresh);
         self.fresh += 1;
         let mut stmts = vec![];
+        let mut tail = Expr::Unit;
         while !matches!(self.peek_token()?, Token::Rbracket) {
             let stmt = self.parse_stmt()?;
-            self.next_token_match(Token::Semicolon)?;
-            stmts.push(stmt);
+            match self.peek_token()?.clone() {
+                Token::Semicolon => {
+                    self.next_token()?;
+                    stmts.push(stmt);
+                }
+                Token::Rbracket if stmt.is_expr() => tail = stmt.into_expr(),
+                _ if stmt.is_block_like() => stmts.push(stmt),
+                _ => return Err(self.unexpected()),
+            }
         }
         self.next_token_match(Token::Rbracket)?;
-        Ok(Expr::Block(stmts, Box::new(Expr::Unit), l))
+        Ok(Expr::Block(stmts, Box::new(tail), l))
+    }
+
+    fn parse_args(&mut self) -> ParseResult<Vec<Expr>> {
+        self.next_token_match(Token::Lparen)?;
+        let mut args = vec![];
+        while !matches!(self.peek_token()?, Token::Rparen) {
+            args.push(self.parse_expr()?);
+            if !matches!(self.peek_token()?, Token::Rparen) {
+                self.next_token_match(Token::Comma)?;
+            }
+        }
+        self.next_token_match(Token::Rparen)?;
+        Ok(args)
+    }
+
+    fn parse_ident(&mut self) -> ParseResult<Ident> {
+        match self.next_token()? {
+            Token::Var(s) => Ok(s),
+            t => Err(Error::Unexpected(t, self.last)),
+        }
+    }
+
+    // Types in signatures: `()`, `i32`, `bool`, `Box<T>`, `&T` and `&mut T`,
+    // where references may name a lifetime as in `&'a T`.
+    fn parse_type(&mut self) -> ParseResult<Type> {
+        match self.next_token()? {
+            Token::Lparen => {
+                self.next_token_match(Token::Rparen)?;
+                Ok(Type::Unit)
+            }
+            Token::Ampersand => {
+                let region = match self.peek_token()?.clone() {
+                    Token::Lifetime(name) => {
+                        self.next_token()?;
+                        Region::Named(name.into())
+                    }
+                    _ => Region::Inferred,
+                };
+                let is_mut = matches!(self.peek_token()?, Token::Mut);
+                if is_mut {
+                    self.next_token()?;
+                }
+                Ok(Type::Borrowed(Box::new(self.parse_type()?), is_mut, region))
+            }
+            Token::Var(s) if s == "i32" => Ok(Type::Int),
+            Token::Var(s) if s == "bool" => Ok(Type::Bool),
+            Token::Var(s) if s == "Box" => {
+                self.next_token_match(Token::Lt)?;
+                let inner = self.parse_type()?;
+                self.next_token_match(Token::Gt)?;
+                Ok(Type::boxx(inner))
+            }
+            t => Err(Error::Unexpected(t, self.last)),
+        }
+    }
+
+    fn parse_fn(&mut self) -> ParseResult<FnDef> {
+        self.next_token_match(Token::Fn)?;
+        let name = self.parse_ident()?;
+        let span = self.last;
+        let mut lifetimes = vec![];
+        if matches!(self.peek_token()?, Token::Lt) {
+            self.next_token()?;
+            while !matches!(self.peek_token()?, Token::Gt) {
+                match self.next_token()? {
+                    Token::Lifetime(name) => lifetimes.push(name),
+                    t => return Err(Error::Unexpected(t, self.last)),
+                }
+                if !matches!(self.peek_token()?, Token::Gt) {
+                    self.next_token_match(Token::Comma)?;
+                }
+            }
+            self.next_token()?;
+        }
+        self.next_token_match(Token::Lparen)?;
+        let mut params = vec![];
+        while !matches!(self.peek_token()?, Token::Rparen) {
+            let param = self.parse_ident()?;
+            self.next_token_match(Token::Colon)?;
+            params.push((param, self.parse_type()?));
+            if !matches!(self.peek_token()?, Token::Rparen) {
+                self.next_token_match(Token::Comma)?;
+            }
+        }
+        self.next_token_match(Token::Rparen)?;
+        let ret = if matches!(self.peek_token()?, Token::Arrow) {
+            self.next_token()?;
+            self.parse_type()?
+        } else {
+            Type::Unit
+        };
+        let body = self.parse_spanned_block()?;
+        Ok(FnDef { name, lifetimes, params, ret, body, span })
+    }
+
+    pub fn parse_program(&mut self) -> ParseResult<Program> {
+        let mut program = Program::default();
+        while self.lexer.peek().is_some() {
+            program.fns.push(self.parse_fn()?);
+        }
+        Ok(program)
     }
 
     pub fn parse(&mut self) -> ParseResult<Expr> {
@@ -144,9 +401,9 @@ impl<'a> Parser<'a> {
             Token::Var(s) if s == "main" => {
                 self.next_token_match(Token::Lparen)?;
                 self.next_token_match(Token::Rparen)?;
-                self.parse_block()
+                self.parse_spanned_block()
             }
-            t => Err(Error::Unexpected(t)),
+            t => Err(Error::Unexpected(t, self.last)),
         }
     }
 }
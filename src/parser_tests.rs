@@ -0,0 +1,142 @@
+use crate::lexer::{Error as LexError, Lexer, Token};
+use crate::parser::{Error, Parser};
+use crate::types::{Region, Type};
+use crate::utils::{BinOp, Expr, Span, Stmt};
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_spans() {
+	let toks: Vec<_> = Lexer::new("let mut\n  xy = 12;").map(|t| t.unwrap()).collect();
+	assert_eq!(toks[2], (Token::Var(String::from("xy")), Span { start: 10, end: 12, line: 2, col: 3 }));
+	assert_eq!(toks[4], (Token::Int(12), Span { start: 15, end: 17, line: 2, col: 8 }));
+    }
+
+    #[test]
+    fn unknown_token_span() {
+	let err = Parser::new("fn main() {\n  let mut x = $;\n}").parse().unwrap_err();
+	assert_eq!(err.span(), Span { start: 26, end: 27, line: 2, col: 15 });
+    }
+
+    #[test]
+    fn unexpected_span() {
+	match Parser::new("fn main() { let 1 = 1; }").parse() {
+	    Err(Error::Unexpected(Token::Int(n), span)) => {
+		assert_eq!(n, 1);
+		assert_eq!((span.line, span.col), (1, 17));
+	    }
+	    r => panic!("unexpected result {:?}", r),
+	}
+    }
+
+    #[test]
+    fn stmt_spans() {
+	let src = "fn main() { let mut x = Box::new(1); }";
+	let e = Parser::new(src).parse().unwrap();
+	if let Expr::Block(stmts, _, _) = e.unspanned() {
+	    if let Stmt::At(_, span) = &stmts[0] {
+		assert_eq!(&src[span.start..span.end], "let mut x = Box::new(1)");
+		return;
+	    }
+	}
+	unreachable!();
+    }
+
+    #[test]
+    fn block_tail() {
+	let e = Parser::new("fn main() { let mut x = 1; x }").parse().unwrap();
+	match e.unspanned() {
+	    Expr::Block(stmts, tail, _) => {
+		assert_eq!(stmts.len(), 1);
+		assert!(matches!(tail.unspanned(), Expr::Lval(..)));
+	    }
+	    _ => unreachable!(),
+	}
+    }
+
+    #[test]
+    fn block_statement_without_semicolon() {
+	let e = Parser::new("fn main() { { let mut x = 1; } let mut y = 2; }").parse().unwrap();
+	assert!(matches!(e.unspanned(), Expr::Block(stmts, tail, _) if stmts.len() == 2 && **tail == Expr::Unit));
+    }
+
+    #[test]
+    fn let_requires_semicolon() {
+	assert!(Parser::new("fn main() { let mut x = 1 }").parse().is_err());
+    }
+
+    #[test]
+    fn chained_comparison() {
+	assert!(Parser::new("fn main() { 1 < 2 < 3 }").parse().is_err());
+	assert!(Parser::new("fn main() { 1 + 2 < 3 * 4 }").parse().is_ok());
+    }
+
+    #[test]
+    fn binop_precedence() {
+	let e = Parser::new("fn main() { 1 - 2 * 3 + 4 }").parse().unwrap();
+	let Expr::Block(_, tail, _) = e.unspanned() else { unreachable!() };
+	let Expr::BinOp(BinOp::Add, left, _) = tail.unspanned() else { unreachable!() };
+	let Expr::BinOp(BinOp::Sub, _, right) = left.unspanned() else { unreachable!() };
+	assert!(matches!(right.unspanned(), Expr::BinOp(BinOp::Mul, _, _)));
+    }
+
+    #[test]
+    fn deref_after_block_statement() {
+	let e = Parser::new("fn main() { let mut x = Box::new(1); { 2 } *x = 3; }").parse().unwrap();
+	assert!(matches!(e.unspanned(), Expr::Block(stmts, _, _) if stmts.len() == 3));
+    }
+
+    #[test]
+    fn keywords_are_whole_words() {
+	let toks: Vec<_> = Lexer::new("iffy if truex").map(|t| t.unwrap().0).collect();
+	assert_eq!(toks, vec![Token::Var(String::from("iffy")), Token::If, Token::Var(String::from("truex"))]);
+    }
+
+    #[test]
+    fn int_literal_out_of_range() {
+	let toks: Vec<_> = Lexer::new("2147483647 99999999999").collect();
+	assert_eq!(toks[0], Ok((Token::Int(i32::MAX), Span { start: 0, end: 10, line: 1, col: 1 })));
+	assert_eq!(toks[1], Err(LexError::IntOverflow(Span { start: 11, end: 22, line: 1, col: 12 })));
+	let err = Parser::new("fn main() { 99999999999 }").parse().unwrap_err();
+	assert_eq!(err.span(), Span { start: 12, end: 23, line: 1, col: 13 });
+    }
+
+    #[test]
+    fn non_ascii_input() {
+	let toks: Vec<_> = Lexer::new("let mut aé = 1;").collect();
+	assert_eq!(toks[2], Ok((Token::Var(String::from("a")), Span { start: 8, end: 9, line: 1, col: 9 })));
+	assert_eq!(toks[3], Err(LexError::Unknown(Span { start: 9, end: 11, line: 1, col: 10 })));
+	assert!(Parser::new("fn main() { let mut aé = 1; }").parse().is_err());
+	assert!(Lexer::new("é").next().unwrap().is_err());
+    }
+
+    #[test]
+    fn program_signatures() {
+	let src = "fn add(a: i32, b: &mut Box<i32>) -> i32 { a }\nfn main() { add(1, &mut x); }";
+	let program = Parser::new(src).parse_program().unwrap();
+	assert_eq!(program.fns.len(), 2);
+	let add = program.get("add").unwrap();
+	assert_eq!(add.params[1], (String::from("b"), Type::Borrowed(Box::new(Type::boxx(Type::Int)), true, Region::Inferred)));
+	assert_eq!(add.ret, Type::Int);
+	assert_eq!(program.get("main").unwrap().ret, Type::Unit);
+    }
+
+    #[test]
+    fn lifetime_params() {
+	let toks: Vec<_> = Lexer::new("&'a mut").map(|t| t.unwrap().0).collect();
+	assert_eq!(toks, vec![Token::Ampersand, Token::Lifetime(String::from("a")), Token::Mut]);
+	let src = "fn longest<'a, 'b>(x: &'a Box<i32>, y: &'b i32) -> &'a Box<i32> { x }";
+	let f = &Parser::new(src).parse_program().unwrap().fns[0];
+	assert_eq!(f.lifetimes, vec![String::from("a"), String::from("b")]);
+	assert_eq!(f.ret, Type::Borrowed(Box::new(Type::boxx(Type::Int)), false, Region::Named("a".into())));
+    }
+
+    #[test]
+    fn immutable_let() {
+	let e = Parser::new("fn main() { let x = 1; }").parse().unwrap();
+	let Expr::At(block, _) = e else { panic!("expected a spanned block") };
+	let Expr::Block(stmts, _, _) = *block else { panic!("expected a block") };
+	assert!(matches!(&stmts[0], Stmt::At(stmt, _) if matches!(**stmt, Stmt::Let(ref x, Expr::At(..)) if x == "x")));
+    }
+}
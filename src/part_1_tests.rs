@@ -1,5 +1,7 @@
-use crate::eval::{Context, Store, Value};
-use crate::utils::{Expr, Lifetime, Lval};
+use crate::eval::{Context, RuntimeError, Sites, Store, Value};
+use crate::parser::Parser;
+use crate::types::TypeContext;
+use crate::utils::{BinOp, Expr, Lifetime, Located, Lval, Stmt};
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -30,6 +32,20 @@ mod tests {
 	store.locate(&Lval::new("x", 1));
     }
 
+    #[test]
+    fn try_locate_non_ref() {
+	let mut store = Store::default();
+	store.insert("x", Some(Value::Int(1)), Lifetime::global());
+	assert_eq!(store.try_locate(&Lval::new("x", 1)), Err(RuntimeError::InvalidDeref(Lval::new("x", 1))));
+    }
+
+    #[test]
+    fn try_read_dangling() {
+	let mut store = Store::default();
+	store.insert("x", Some(Value::Ref(String::from("1"), false)), Lifetime::global());
+	assert_eq!(store.try_read(&Lval::new("x", 1)), Err(RuntimeError::DanglingLocation(String::from("1"))));
+    }
+
     #[test]
     fn read_var() {
 	let mut store = Store::default();
@@ -64,9 +80,9 @@ mod tests {
     fn write_two_deref() {
 	let mut store = Store::default();
 	store.insert("2", Some(Value::Int(1)), Lifetime::global());
-	store.insert("x", Some(Value::Ref(String::from("2"), Owned::No)), Lifetime::global());
-	store.insert("y", Some(Value::Ref(String::from("x"), Owned::No)), Lifetime::global());
-	store.insert("z", Some(Value::Ref(String::from("2"), Owned::No)), Lifetime::global());
+	store.insert("x", Some(Value::Ref(String::from("2"), false)), Lifetime::global());
+	store.insert("y", Some(Value::Ref(String::from("x"), false)), Lifetime::global());
+	store.insert("z", Some(Value::Ref(String::from("2"), false)), Lifetime::global());
 	assert_eq!(store.write(&Lval::new("y", 2), Some(Value::Int(5))), Some(Value::Int(1)));
 	let slot_2 = store.read(&Lval::new("y", 2));
 	assert_eq!(slot_2.value, Some(Value::Int(5)));
@@ -76,9 +92,9 @@ mod tests {
     fn write_deref_read_diff() {
 	let mut store = Store::default();
 	store.insert("2", Some(Value::Int(1)), Lifetime::global());
-	store.insert("x", Some(Value::Ref(String::from("2"), Owned::No)), Lifetime::global());
-	store.insert("y", Some(Value::Ref(String::from("x"), Owned::No)), Lifetime::global());
-	store.insert("z", Some(Value::Ref(String::from("2"), Owned::No)), Lifetime::global());
+	store.insert("x", Some(Value::Ref(String::from("2"), false)), Lifetime::global());
+	store.insert("y", Some(Value::Ref(String::from("x"), false)), Lifetime::global());
+	store.insert("z", Some(Value::Ref(String::from("2"), false)), Lifetime::global());
 	assert_eq!(store.write(&Lval::new("y", 2), Some(Value::Int(5))), Some(Value::Int(1)));
 	let slot_2 = store.read(&Lval::new("z", 1));
 	assert_eq!(slot_2.value, Some(Value::Int(5)));
@@ -89,9 +105,9 @@ mod tests {
     fn write_panic() {
 	let mut store = Store::default();
 	store.insert("2", Some(Value::Int(1)), Lifetime::global());
-	store.insert("x", Some(Value::Ref(String::from("2"), Owned::No)), Lifetime::global());
-	store.insert("y", Some(Value::Ref(String::from("x"), Owned::No)), Lifetime::global());
-	store.insert("z", Some(Value::Ref(String::from("2"), Owned::No)), Lifetime::global());
+	store.insert("x", Some(Value::Ref(String::from("2"), false)), Lifetime::global());
+	store.insert("y", Some(Value::Ref(String::from("x"), false)), Lifetime::global());
+	store.insert("z", Some(Value::Ref(String::from("2"), false)), Lifetime::global());
 	store.write(&Lval::new("y", 3), Some(Value::Int(5)));
     }
 
@@ -101,18 +117,18 @@ mod tests {
 	store.insert("x", Some(Value::Ref(String::from("1"), true)
 ), Lifetime::global());
 	store.insert("1", Some(Value::Int(1)), Lifetime::global());
-	store.drop(vec![Some(Value::Ref(String::from("x"), Owned::Yes))]);
+	store.drop(vec![Some(Value::Ref(String::from("x"), true))]);
 	assert_eq!(store, Store::default());
     }
 
     #[test]
     fn drop_unowned() {
 	let mut store = Store::default();
-	store.insert("x", Some(Value::Ref(String::from("1"), Owned::No)), Lifetime::global());
+	store.insert("x", Some(Value::Ref(String::from("1"), false)), Lifetime::global());
 	store.insert("1", Some(Value::Int(1)), Lifetime::global());
 	let mut store_2 = Store::default();
 	store_2.insert("1", Some(Value::Int(1)), Lifetime::global());
-	store.drop(vec![Some(Value::Ref(String::from("x"), Owned::Yes))]);
+	store.drop(vec![Some(Value::Ref(String::from("x"), true))]);
 	assert_eq!(store, store_2);
     }
 
@@ -121,25 +137,35 @@ mod tests {
 	let mut store = Store::default();
 	store.insert("x", Some(Value::Ref(String::from("1"), true)
 ), Lifetime(1));
-	store.insert("1", Some(Value::Ref(String::from("2"), Owned::No)), Lifetime(2));
+	store.insert("1", Some(Value::Ref(String::from("2"), false)), Lifetime(2));
 	store.insert("2", Some(Value::Int(1)), Lifetime(1));
-	store.insert("y", Some(Value::Ref(String::from("x"), Owned::No)), Lifetime(2));
-	store.insert("z", Some(Value::Ref(String::from("2"), Owned::Yes)), Lifetime(2));
-	store.insert("w", Some(Value::Ref(String::from("3"), Owned::Yes)), Lifetime(1));
+	store.insert("y", Some(Value::Ref(String::from("x"), false)), Lifetime(2));
+	store.insert("z", Some(Value::Ref(String::from("2"), true)), Lifetime(2));
+	store.insert("w", Some(Value::Ref(String::from("3"), true)), Lifetime(1));
 	store.insert("3", Some(Value::Int(2)), Lifetime(2));
-	store.insert("v", Some(Value::Ref(String::from("1"), Owned::No)), Lifetime(1));
+	store.insert("v", Some(Value::Ref(String::from("1"), false)), Lifetime(1));
 	store.drop(store.locs_by_lifetime(Lifetime(1)));
 	let mut store_2 = Store::default();
-	store_2.insert("y", Some(Value::Ref(String::from("x"), Owned::No)), Lifetime(2));
-	store_2.insert("z", Some(Value::Ref(String::from("2"), Owned::Yes)), Lifetime(2));
+	store_2.insert("y", Some(Value::Ref(String::from("x"), false)), Lifetime(2));
+	store_2.insert("z", Some(Value::Ref(String::from("2"), true)), Lifetime(2));
 	assert_eq!(store, store_2);
     }
 
+    #[test]
+    fn drop_box_box() {
+	let mut store = Store::default();
+	store.insert("x", Some(Value::Ref(String::from("1"), true)), Lifetime(1));
+	store.insert("1", Some(Value::Ref(String::from("2"), true)), Lifetime::global());
+	store.insert("2", Some(Value::Int(1)), Lifetime::global());
+	store.drop(store.locs_by_lifetime(Lifetime(1)));
+	assert_eq!(store, Store::default());
+    }
+
     #[test]
     fn eval_lits() {
 	let mut context = Context::default();
-	assert_eq!(context.eval_expr(&Expr::Unit, Lifetime::global()), Value::Unit);
-	assert_eq!(context.eval_expr(&Expr::Int(234), Lifetime::global()), Value::Int(234));
+	assert_eq!(context.eval_expr(&Expr::Unit, Lifetime::global()), Ok(Value::Unit));
+	assert_eq!(context.eval_expr(&Expr::Int(234), Lifetime::global()), Ok(Value::Int(234)));
 	assert_eq!(context.store, Store::default());
     }
 
@@ -148,7 +174,7 @@ mod tests {
 	let mut context = Context::default();
 	context.store.insert("x", Some(Value::Int(34)), Lifetime(1));
 	let store_2 = context.store.clone();
-	assert_eq!(context.eval_expr(&Expr::Lval(Lval::new("x", 0), Copyable::Yes), Lifetime::global()), Value::Int(34));
+	assert_eq!(context.eval_expr(&Expr::Lval(Lval::new("x", 0), true), Lifetime::global()), Ok(Value::Int(34)));
 	assert_eq!(context.store, store_2);
     }
 
@@ -158,20 +184,20 @@ mod tests {
 	context.store.insert("x", Some(Value::Int(5)), Lifetime(1));
 	let mut store_2 = Store::default();
 	store_2.insert("x", None, Lifetime(1));
-	assert_eq!(context.eval_expr(&Expr::Lval(Lval::new("x", 0), Copyable::No), Lifetime::global()), Value::Int(5));
+	assert_eq!(context.eval_expr(&Expr::Lval(Lval::new("x", 0), false), Lifetime::global()), Ok(Value::Int(5)));
 	assert_eq!(context.store, store_2);
     }
 
     #[test]
     fn eval_box() {
 	let mut context = Context::default();
-	if let Value::Ref(loc, _) = context.eval_expr(&Expr::Box(Box::new(Expr::Int(-1))), Lifetime(5)) {
+	if let Ok(Value::Ref(loc, _)) = context.eval_expr(&Expr::Box(Box::new(Expr::Int(-1))), Lifetime(5)) {
 	    let mut store_2 = Store::default();
-	    store_2.insert(&loc, Some(Value::Int(-1)), Lifetime::global());
+	    assert_eq!(store_2.alloc(Some(Value::Int(-1))), loc);
 	    assert_eq!(context.store.read(&Lval::new(&loc, 0)).value, Some(Value::Int(-1)));
 	    assert_eq!(context.store, store_2);
 	} else {
-	    assert!(false);
+	    unreachable!();
 	}
     }
 
@@ -179,7 +205,7 @@ mod tests {
     fn eval_box_box() {
 	let mut context = Context::default();
 	let box_box = Expr::Box(Box::new(Expr::Box(Box::new(Expr::Int(12)))));
-	if let Value::Ref(loc, _) = context.eval_expr(&box_box, Lifetime(34)) {
+	if let Ok(Value::Ref(loc, _)) = context.eval_expr(&box_box, Lifetime(34)) {
 	    assert_eq!(context.store.read(&Lval::new(&loc, 1)).value, Some(Value::Int(12)));
 	}
     }
@@ -187,16 +213,16 @@ mod tests {
     #[test]
     fn eval_let_mut() {
 	let mut context = Context::default();
-	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(14)))), Lifetime(4));
+	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(14)))), Lifetime(4)).unwrap();
 	assert_eq!(context.store.read(&Lval::new("x", 1)).value, Some(Value::Int(14)));
     }
 
     #[test]
     fn eval_assign_copy() {
 	let mut context = Context::default();
-	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(14)))), Lifetime(4));
-	context.eval_stmt(&Stmt::LetMut(String::from("y"), Expr::Box(Box::new(Expr::Int(15)))), Lifetime(4));
-	context.eval_stmt(&Stmt::Assign(Lval::new("x", 0), Expr::Lval(Lval::new("y", 0), Copyable::Yes)), Lifetime(4));
+	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(14)))), Lifetime(4)).unwrap();
+	context.eval_stmt(&Stmt::LetMut(String::from("y"), Expr::Box(Box::new(Expr::Int(15)))), Lifetime(4)).unwrap();
+	context.eval_stmt(&Stmt::Assign(Lval::new("x", 0), Expr::Lval(Lval::new("y", 0), true)), Lifetime(4)).unwrap();
 	assert_eq!(context.store.read(&Lval::new("x", 1)).value, Some(Value::Int(15)));
 	assert_eq!(context.store.read(&Lval::new("y", 1)).value, Some(Value::Int(15)));
     }
@@ -204,9 +230,9 @@ mod tests {
     #[test]
     fn eval_assign_move() {
 	let mut context = Context::default();
-	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(14)))), Lifetime(4));
-	context.eval_stmt(&Stmt::LetMut(String::from("y"), Expr::Box(Box::new(Expr::Int(15)))), Lifetime(4));
-	context.eval_stmt(&Stmt::Assign(Lval::new("x", 0), Expr::Lval(Lval::new("y", 0), Copyable::No)), Lifetime(4));
+	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(14)))), Lifetime(4)).unwrap();
+	context.eval_stmt(&Stmt::LetMut(String::from("y"), Expr::Box(Box::new(Expr::Int(15)))), Lifetime(4)).unwrap();
+	context.eval_stmt(&Stmt::Assign(Lval::new("x", 0), Expr::Lval(Lval::new("y", 0), false)), Lifetime(4)).unwrap();
 	assert_eq!(context.store.read(&Lval::new("x", 1)).value, Some(Value::Int(15)));
 	assert_eq!(context.store.read(&Lval::new("y", 0)).value, None);
     }
@@ -214,20 +240,30 @@ mod tests {
     #[test]
     fn eval_assign_replace() {
 	let mut context = Context::default();
-	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(14)))), Lifetime(4));
-	context.eval_stmt(&Stmt::LetMut(String::from("y"), Expr::Box(Box::new(Expr::Int(15)))), Lifetime(4));
-	context.eval_stmt(&Stmt::Assign(Lval::new("x", 0), Expr::Lval(Lval::new("y", 0), Copyable::No)), Lifetime(4));
-	context.eval_stmt(&Stmt::Assign(Lval::new("y", 0), Expr::Box(Box::new(Expr::Int(16)))), Lifetime(4));
+	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(14)))), Lifetime(4)).unwrap();
+	context.eval_stmt(&Stmt::LetMut(String::from("y"), Expr::Box(Box::new(Expr::Int(15)))), Lifetime(4)).unwrap();
+	context.eval_stmt(&Stmt::Assign(Lval::new("x", 0), Expr::Lval(Lval::new("y", 0), false)), Lifetime(4)).unwrap();
+	context.eval_stmt(&Stmt::Assign(Lval::new("y", 0), Expr::Box(Box::new(Expr::Int(16)))), Lifetime(4)).unwrap();
 	assert_eq!(context.store.read(&Lval::new("x", 1)).value, Some(Value::Int(15)));
 	assert_eq!(context.store.read(&Lval::new("y", 1)).value, Some(Value::Int(16)));
     }
 
+    #[test]
+    fn eval_assign_drops_old() {
+	let mut context = Context::default();
+	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Box(Box::new(Expr::Int(1)))))), Lifetime(4)).unwrap();
+	context.eval_stmt(&Stmt::Assign(Lval::new("x", 0), Expr::Int(2)), Lifetime(4)).unwrap();
+	let mut store_2 = Store::default();
+	store_2.insert("x", Some(Value::Int(2)), Lifetime(4));
+	assert_eq!(context.store, store_2);
+    }
+
     #[test]
     fn eval_assign_move_deref() {
 	let mut context = Context::default();
-	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(14)))), Lifetime(4));
-	context.eval_stmt(&Stmt::LetMut(String::from("y"), Expr::Box(Box::new(Expr::Int(15)))), Lifetime(4));
-	context.eval_stmt(&Stmt::Assign(Lval::new("x", 1), Expr::Lval(Lval::new("y", 1), Copyable::No)), Lifetime(4));
+	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(14)))), Lifetime(4)).unwrap();
+	context.eval_stmt(&Stmt::LetMut(String::from("y"), Expr::Box(Box::new(Expr::Int(15)))), Lifetime(4)).unwrap();
+	context.eval_stmt(&Stmt::Assign(Lval::new("x", 1), Expr::Lval(Lval::new("y", 1), false)), Lifetime(4)).unwrap();
 	assert_eq!(context.store.read(&Lval::new("x", 1)).value, Some(Value::Int(15)));
 	assert_eq!(context.store.read(&Lval::new("y", 1)).value, None);
     }
@@ -236,11 +272,25 @@ mod tests {
     #[test]
     fn eval_expr_stmt() {
 	let mut context = Context::default();
-	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(14)))), Lifetime(4));
-	context.eval_stmt(&Stmt::Expr(Expr::Lval(Lval::new("x", 1), Copyable::No)), Lifetime(4));
+	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(14)))), Lifetime(4)).unwrap();
+	context.eval_stmt(&Stmt::Expr(Expr::Lval(Lval::new("x", 1), false)), Lifetime(4)).unwrap();
 	assert_eq!(context.store.read(&Lval::new("x", 1)).value, None);
     }
 
+    #[test]
+    fn eval_assert_fails() {
+	let mut context = Context::default();
+	let e = Expr::AssertEq(Box::new(Expr::Int(1)), Box::new(Expr::Int(2)));
+	assert_eq!(context.eval_expr(&e, Lifetime::global()), Err(RuntimeError::AssertionFailed(Value::Int(1), Value::Int(2))));
+    }
+
+    #[test]
+    fn eval_use_after_move() {
+	let mut context = Context::default();
+	context.store.insert("x", None, Lifetime(1));
+	assert_eq!(context.eval_expr(&Expr::Lval(Lval::var("x"), false), Lifetime(1)), Err(RuntimeError::UseAfterMove(Lval::var("x"))));
+    }
+
     #[test]
     fn eval_block() {
 	let mut context = Context::default();
@@ -249,40 +299,315 @@ mod tests {
 	    Box::new(Expr::Unit),
 	    Lifetime(3),
 	);
-	context.eval_expr(&e, Lifetime(5));
+	context.eval_expr(&e, Lifetime(5)).unwrap();
 	assert_eq!(context.store, Store::default());
     }
 
     #[test]
     fn eval_block_ref() {
 	let mut context = Context::default();
-	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(203)))), Lifetime(4));
+	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(203)))), Lifetime(4)).unwrap();
 	let store_2 = context.store.clone();
 	let e = Expr::Block(
-	    vec![Stmt::LetMut(String::from("y"), Expr::Borrow(Lval::new("x", 1), Mutable::No))],
+	    vec![Stmt::LetMut(String::from("y"), Expr::Borrow(Lval::new("x", 1), false))],
 	    Box::new(Expr::Unit),
 	    Lifetime(6),
 	);
-	context.eval_expr(&e, Lifetime(4));
+	context.eval_expr(&e, Lifetime(4)).unwrap();
 	assert_eq!(context.store, store_2);
     }
 
     #[test]
     fn eval_block_mut_ref() {
 	let mut context = Context::default();
-	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(203)))), Lifetime(4));
+	context.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(203)))), Lifetime(4)).unwrap();
 	let e = Expr::Block(
 	    vec![
-		Stmt::LetMut(String::from("y"), Expr::Borrow(Lval::new("x", 1), Mutable::Yes)),
+		Stmt::LetMut(String::from("y"), Expr::Borrow(Lval::new("x", 1), true)),
 		Stmt::Assign(Lval::new("y", 1), Expr::Int(-150)),
 	    ],
 	    Box::new(Expr::Unit),
 	    Lifetime(6),
 	);
-	context.eval_expr(&e, Lifetime(4));
+	context.eval_expr(&e, Lifetime(4)).unwrap();
 	let mut context_2 = Context::default();
-	context_2.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(-150)))), Lifetime(4));
+	context_2.eval_stmt(&Stmt::LetMut(String::from("x"), Expr::Box(Box::new(Expr::Int(-150)))), Lifetime(4)).unwrap();
 	assert_eq!(context.store, context_2.store);
     }
 
+    #[test]
+    fn eval_block_tail() {
+	let mut context = Context::default();
+	let e = Parser::new("fn main() { let mut y = { let mut x = Box::new(7); x }; assert_eq!(*y, 7); }").parse().unwrap();
+	assert_eq!(context.eval_expr(&e, Lifetime::global()), Ok(Value::Unit));
+	assert_eq!(context.store, Store::default());
+    }
+
+    fn run(src: &str) -> Result<Value, RuntimeError> {
+	let mut e = Parser::new(src).parse().unwrap();
+	TypeContext::new().type_expr(&mut e).unwrap();
+	Context::default().eval_expr(&e, Lifetime::global()).map_err(|e| e.kind().clone())
+    }
+
+    #[test]
+    fn eval_arithmetic() {
+	assert_eq!(run("fn main() { 1 - 2 * 3 + 7 % 4 }"), Ok(Value::Int(-2)));
+	assert_eq!(run("fn main() { (1 + 2) * 3 == 9 }"), Ok(Value::Bool(true)));
+	assert_eq!(run("fn main() { 7 / 2 >= 4 }"), Ok(Value::Bool(false)));
+    }
+
+    #[test]
+    fn eval_checked_arithmetic() {
+	assert_eq!(run("fn main() { 2147483647 + 1 }"), Err(RuntimeError::Overflow(BinOp::Add)));
+	assert_eq!(run("fn main() { let mut x = 0; 1 / x }"), Err(RuntimeError::DivisionByZero(BinOp::Div)));
+	assert_eq!(run("fn main() { 1 % 0 }"), Err(RuntimeError::DivisionByZero(BinOp::Rem)));
+    }
+
+    #[test]
+    fn eval_if() {
+	assert_eq!(run("fn main() { let mut x = 3; if x > 2 { 10 } else if x > 1 { 20 } else { 30 } }"), Ok(Value::Int(10)));
+	assert_eq!(run("fn main() { if false { assert_eq!(1, 2); } else { } }"), Ok(Value::Unit));
+    }
+
+    #[test]
+    fn eval_if_drops_branch() {
+	let mut context = Context::default();
+	let e = Parser::new("fn main() { let mut x = Box::new(1); if true { let mut y = Box::new(2); } *x = 3; }").parse().unwrap();
+	let Expr::Block(stmts, _, _) = e.unspanned() else { unreachable!() };
+	context.eval_stmt(&stmts[0], Lifetime(1)).unwrap();
+	context.eval_stmt(&stmts[1], Lifetime(1)).unwrap();
+	let mut store_2 = Store::default();
+	let loc = store_2.alloc(Some(Value::Int(1)));
+	store_2.insert("x", Some(Value::Ref(loc, true)), Lifetime(1));
+	assert_eq!(context.store, store_2);
+    }
+
+    #[test]
+    fn eval_while() {
+	assert_eq!(run("fn main() { let mut i = 0; let mut sum = 0; while i < 5 { sum = sum + i; i = i + 1; } sum }"), Ok(Value::Int(10)));
+    }
+
+    #[test]
+    fn eval_while_drops_each_iteration() {
+	let mut context = Context::default();
+	let mut e = Parser::new("fn main() { let mut i = 0; while i < 3 { let mut b = Box::new(i); i = i + 1; } }").parse().unwrap();
+	TypeContext::new().type_expr(&mut e).unwrap();
+	context.eval_expr(&e, Lifetime::global()).unwrap();
+	assert_eq!(context.store, Store::default());
+    }
+
+    fn run_program(src: &str) -> Result<Value, RuntimeError> {
+	let mut program = Parser::new(src).parse_program().unwrap();
+	TypeContext::new().type_program(&mut program).unwrap();
+	Context::default().eval_program(&program).map_err(|e| e.kind().clone())
+    }
+
+    #[test]
+    fn eval_recursive_call() {
+	let src = "fn fact(n: i32) -> i32 { let mut r = 1; if n > 1 { r = n * fact(n - 1); } r }\n\
+		   fn main() { assert_eq!(fact(5), 120); }";
+	assert_eq!(run_program(src), Ok(Value::Unit));
+    }
+
+    #[test]
+    fn eval_call_mut_ref() {
+	let src = "fn set(r: &mut i32, v: i32) { *r = v; }\nfn main() { let mut x = 1; set(&mut x, 7); assert_eq!(x, 7); }";
+	assert_eq!(run_program(src), Ok(Value::Unit));
+    }
+
+    #[test]
+    fn eval_call_drops_frame() {
+	let src = "fn f(b: Box<i32>) -> i32 { let mut c = Box::new(2); 3 }\nfn main() { let mut x = f(Box::new(1)); }";
+	let mut program = Parser::new(src).parse_program().unwrap();
+	TypeContext::new().type_program(&mut program).unwrap();
+	let mut ctx = Context::default();
+	ctx.eval_program(&program).unwrap();
+	assert!(ctx.store.slots.keys().all(|loc| loc == "x"));
+    }
+
+    #[test]
+    fn eval_errors_name_source_places() {
+	let src = "fn f(a: Box<i32>) -> i32 { let b = a; *a }\nfn main() { f(Box::new(1)); }";
+	let program = Parser::new(src).parse_program().unwrap();
+	let result = Context::default().eval_program(&program).map_err(|e| e.kind().clone());
+	assert_eq!(result, Err(RuntimeError::UseAfterMove(Lval::new("a", 1))));
+	let src = "fn f(a: Box<i32>) -> i32 { let b = a; let c = a; 1 }\nfn main() { f(Box::new(1)); }";
+	let program = Parser::new(src).parse_program().unwrap();
+	let result = Context::default().eval_program(&program).map_err(|e| e.kind().clone());
+	assert_eq!(result, Err(RuntimeError::UseAfterMove(Lval::var("a"))));
+    }
+
+    #[test]
+    fn eval_implicit_reborrow() {
+	let src = "fn set(r: &mut i32, v: i32) { *r = v; }\n\
+		   fn main() { let mut x = 1; let mut r = &mut x; set(r, 2); set(r, 3); assert_eq!(x, 3); }";
+	assert_eq!(run_program(src), Ok(Value::Unit));
+    }
+
+    #[test]
+    fn eval_copy_shared_ref() {
+	let src = "fn main() { let mut x = 1; let mut r = &x; let mut a = r; let mut b = r; }";
+	let mut e = Parser::new(src).parse().unwrap();
+	TypeContext::new().type_expr(&mut e).unwrap();
+	let mut ctx = Context::default();
+	ctx.eval_expr(&e, Lifetime::global()).unwrap();
+	assert!(ctx.store.slots.is_empty());
+    }
+
+    #[test]
+    fn eval_read_through_derefs() {
+	assert_eq!(run("fn main() { let mut x = Box::new(1); assert_eq!(*x, 1); }"), Ok(Value::Unit));
+	let src = "fn inc(r: &mut i32) { *r = *r + 1; }\nfn main() { let mut x = 1; inc(&mut x); inc(&mut x); assert_eq!(x, 3); }";
+	assert_eq!(run_program(src), Ok(Value::Unit));
+    }
+
+    #[test]
+    fn eval_restore_after_partial_move() {
+	let mut store = Store::default();
+	store.insert("x", Some(Value::Ref(String::from("b"), true)), Lifetime(1));
+	store.insert("b", Some(Value::Int(1)), Lifetime::global());
+	let mut ctx = Context::default();
+	ctx.store = store;
+	let stmt = Stmt::LetMut(String::from("y"), Expr::Lval(Lval::new("x", 1), false));
+	ctx.eval_stmt(&stmt, Lifetime(1)).unwrap();
+	assert_eq!(ctx.store.slots["b"].value, None);
+	assert_eq!(ctx.eval_expr(&Expr::Lval(Lval::new("x", 1), true), Lifetime(1)), Err(RuntimeError::UseAfterMove(Lval::new("x", 1))));
+	ctx.eval_stmt(&Stmt::Assign(Lval::new("x", 1), Expr::Int(2)), Lifetime(1)).unwrap();
+	assert_eq!(ctx.store.slots["b"].value, Some(Value::Int(2)));
+
+	let src = "fn main() { let mut x = Box::new(Box::new(1)); let mut y = *x; *x = Box::new(2); assert_eq!(**x, 2); }";
+	assert_eq!(run(src), Ok(Value::Unit));
+    }
+
+    #[test]
+    fn eval_shadowing() {
+	let src = "fn main() { let x = Box::new(1); let r = &x; let x = Box::new(2); assert_eq!(**r, 1); assert_eq!(*x, 2); }";
+	let mut e = Parser::new(src).parse().unwrap();
+	TypeContext::new().type_expr(&mut e).unwrap();
+	let mut ctx = Context::default();
+	assert_eq!(ctx.eval_expr(&e, Lifetime::global()), Ok(Value::Unit));
+	assert_eq!(ctx.store, Store::default());
+	assert_eq!(run("fn main() { let x = 1; { let x = 2; assert_eq!(x, 2); }; assert_eq!(x, 1); }"), Ok(Value::Unit));
+    }
+
+    #[test]
+    fn eval_drop_order() {
+	let src = "fn main() { let a = Box::new(1); let b = 2; let c = a; let b = true; { let d = 3; } }";
+	let mut e = Parser::new(src).parse().unwrap();
+	TypeContext::new().type_expr(&mut e).unwrap();
+	let Expr::Block(_, tail, outer) = e.unspanned() else { unreachable!() };
+	let Expr::Block(_, _, inner) = tail.unspanned() else { unreachable!() };
+	let mut ctx = Context::default();
+	ctx.eval_expr(&e, Lifetime::global()).unwrap();
+	let drops: Vec<(&str, Lifetime)> = ctx.drops.iter().map(|(loc, l)| (loc.as_str(), l.clone())).collect();
+	assert_eq!(drops, vec![
+	    ("d", inner.clone()),
+	    ("b", outer.clone()),
+	    ("c", outer.clone()),
+	    ("loc_0", Lifetime::global()),
+	    ("b~1", outer.clone()),
+	    ("a", outer.clone()),
+	]);
+    }
+
+    #[test]
+    fn eval_drop_on_assign() {
+	let mut ctx = Context::default();
+	ctx.store.insert("x", Some(Value::Int(1)), Lifetime(1));
+	let e = Parser::new("fn main() { let mut y = Box::new(1); y = Box::new(2); }").parse().unwrap();
+	let Expr::Block(stmts, _, _) = e.unspanned() else { unreachable!() };
+	ctx.eval_stmt(&stmts[0], Lifetime(1)).unwrap();
+	ctx.eval_stmt(&stmts[1], Lifetime(1)).unwrap();
+	assert_eq!(ctx.drops, vec![(String::from("loc_0"), Lifetime::global())]);
+	ctx.eval_expr(&Expr::Block(vec![], Box::new(Expr::Unit), Lifetime(1)), Lifetime::global()).unwrap();
+	let drops: Vec<&str> = ctx.drops.iter().map(|(loc, _)| loc.as_str()).collect();
+	assert_eq!(drops, vec!["loc_0", "y", "loc_1", "x"]);
+    }
+
+    #[test]
+    fn eval_fresh_heap_locations() {
+	assert_eq!(run("fn main() { let mut a = Box::new(1); a = Box::new(2); let b = Box::new(3); assert_eq!(*a, 2); assert_eq!(*b, 3); }"), Ok(Value::Unit));
+	let src = "fn main() { let mut i = 0; while i < 3 { let b = Box::new(i); i = i + 1; } }";
+	let heap_drops = |mut ctx: Context| {
+	    let mut e = Parser::new(src).parse().unwrap();
+	    TypeContext::new().type_expr(&mut e).unwrap();
+	    ctx.eval_expr(&e, Lifetime::global()).unwrap();
+	    ctx.drops.into_iter().map(|(loc, _)| loc).filter(|loc| loc.starts_with("loc_")).collect::<Vec<_>>()
+	};
+	assert_eq!(heap_drops(Context::default()), vec!["loc_0", "loc_1", "loc_2"]);
+	assert_eq!(heap_drops(Context::reusing_addresses()), vec!["loc_0", "loc_0", "loc_0"]);
+    }
+
+    #[test]
+    fn heap_address_reuse() {
+	let mut store = Store::default();
+	let a = store.alloc(Some(Value::Int(1)));
+	store.insert("r", Some(Value::Ref(a.clone(), false)), Lifetime::global());
+	store.drop(vec![Some(Value::Ref(a.clone(), true))]);
+	assert_ne!(store.alloc(Some(Value::Int(2))), a);
+	assert_eq!(store.try_read(&Lval::new("r", 1)), Err(RuntimeError::DanglingLocation(a)));
+
+	let mut store = Store::default();
+	store.heap.reuse = true;
+	let a = store.alloc(Some(Value::Int(1)));
+	store.insert("r", Some(Value::Ref(a.clone(), false)), Lifetime::global());
+	store.drop(vec![Some(Value::Ref(a.clone(), true))]);
+	assert_eq!(store.alloc(Some(Value::Int(2))), a);
+	assert_eq!(store.read(&Lval::new("r", 1)).value, Some(Value::Int(2)));
+
+	let mut used = Store::default();
+	used.alloc(None);
+	used.drop(vec![Some(Value::Ref(String::from("loc_0"), true))]);
+	assert_eq!(used, Store::default());
+	assert_ne!(Context::checked().store, Store::default());
+	assert_ne!(Context::reusing_addresses().store, Store::default());
+    }
+
+    fn run_with(mut ctx: Context, src: &str) -> Result<Value, RuntimeError> {
+	let e = Parser::new(src).parse().unwrap();
+	ctx.eval_expr(&e, Lifetime::global()).map_err(|e| e.kind().clone())
+    }
+
+    #[test]
+    fn checked_use_after_free() {
+	// None of these would get past the type checker.
+	let src = "fn main() { let b = Box::new(1); let r = &*b; { let c = b; }; *r }";
+	assert_eq!(run_with(Context::default(), src), Err(RuntimeError::DanglingLocation(String::from("loc_0"))));
+	assert!(matches!(run_with(Context::checked(), src), Err(RuntimeError::UseAfterFree(loc, Sites { allocated: Some(_), freed: Some(_) })) if loc == "loc_0.1"));
+
+	let src = "fn main() { let b = Box::new(1); let r = &*b; { let c = b; }; let d = Box::new(2); *r }";
+	assert_eq!(run_with(Context::reusing_addresses(), src), Ok(Value::Int(2)));
+	let mut ctx = Context::checked();
+	ctx.store.heap.reuse = true;
+	assert!(matches!(run_with(ctx, src), Err(RuntimeError::UseAfterFree(loc, _)) if loc == "loc_0.1"));
+
+	let mut ctx = Context::checked();
+	ctx.store.insert("r", Some(Value::Ref(String::from("loc_9"), false)), Lifetime::global());
+	assert_eq!(run_with(ctx, "fn main() { *r = 1; }"), Err(RuntimeError::Unallocated(String::from("loc_9"))));
+    }
+
+    #[test]
+    fn checked_double_free() {
+	let block = Expr::Block(vec![], Box::new(Expr::Unit), Lifetime(1));
+	let setup = |mut ctx: Context| {
+	    let loc = ctx.store.alloc(Some(Value::Int(1)));
+	    ctx.store.insert("a", Some(Value::Ref(loc.clone(), true)), Lifetime(1));
+	    ctx.store.insert("b", Some(Value::Ref(loc.clone(), true)), Lifetime(1));
+	    (ctx, loc)
+	};
+	let (mut ctx, _) = setup(Context::default());
+	assert_eq!(ctx.eval_expr(&block, Lifetime::global()), Ok(Value::Unit));
+	let (mut ctx, loc) = setup(Context::checked());
+	assert_eq!(ctx.eval_expr(&block, Lifetime::global()), Err(RuntimeError::DoubleFree(loc, Sites::default())));
+    }
+
+    #[test]
+    fn checked_leaks() {
+	let program = Parser::new("fn main() { let b = Box::new(Box::new(1)); Box::new(2); }").parse_program().unwrap();
+	assert_eq!(Context::checked().eval_program(&program), Ok(Value::Unit));
+	let mut ctx = Context::checked();
+	ctx.eval_expr(&Expr::Box(Box::new(Expr::Int(0))), Lifetime::global()).unwrap();
+	let leak = (String::from("loc_0.1"), Sites::default());
+	assert_eq!(ctx.eval_program(&program), Err(RuntimeError::Leaked(vec![leak])));
+    }
 }
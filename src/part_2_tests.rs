@@ -1,3 +1,5 @@
+use crate::types::{Env, Slot, Type};
+use crate::utils::{Lifetime, Lval};
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -71,10 +73,10 @@ mod tests {
 	let mut env = Env::default();
 	env.insert("x", Type::boxx(Type::boxx(Type::boxx(Type::Int))), Lifetime(40));
 	assert!(env.moove(&Lval::new("x", 2)).is_ok());
-	if let Some(slot) = env.0.get("x") {
+	if let Some(slot) = env.slots.get("x") {
 	    assert_eq!(slot.tipe, Type::boxx(Type::boxx(Type::undefined(Type::boxx(Type::Int)))));
 	} else {
-	    assert!(false);
+	    unreachable!();
 	}
     }
 
@@ -139,10 +141,10 @@ mod tests {
 	let mut env = Env::default();
 	env.insert("x", Type::boxx(Type::boxx(Type::undefined(Type::boxx(Type::Int)))), Lifetime(23));
 	assert!(env.write(&Lval::new("x", 2), Type::boxx(Type::Int)).is_ok());
-	if let Some(slot) = env.0.get("x") {
+	if let Some(slot) = env.slots.get("x") {
 	    assert_eq!(slot.tipe, Type::boxx(Type::boxx(Type::boxx(Type::Int))));
 	} else {
-	    assert!(false);
+	    unreachable!();
 	}
     }
 
@@ -1,146 +1,987 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use crate::liveness;
 use crate::utils::*;
 
+// How long a reference is valid for: a lifetime named in a signature, or
+// whatever the borrowed location's own lifetime is. Names are boxed to keep
+// `Type` small.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Region {
+    Named(Box<str>),
+    Inferred,
+}
+
+impl Region {
+    // Whether the name was made up by elision rather than written.
+    pub fn elided(&self) -> bool {
+        matches!(self, Region::Named(name) if name.starts_with(|c: char| c.is_ascii_digit()))
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Region::Named(_) if self.elided() => Ok(()),
+            Region::Named(name) => write!(f, "'{} ", name),
+            Region::Inferred => Ok(()),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Type {
     Unit,
     Int,
+    Bool,
     Box(Box<Type>),
-    Ref(Lval, bool),
+    // A reference to whichever of the places it may borrow, depending on the
+    // path taken to reach it.
+    Ref(BTreeSet<Lval>, bool, Region),
+    // A reference in a function signature, whose target lies outside the function.
+    Borrowed(Box<Type>, bool, Region),
     Undefined(Box<Type>),
 }
 
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Unit => write!(f, "()"),
+            Type::Int => write!(f, "i32"),
+            Type::Bool => write!(f, "bool"),
+            Type::Box(inner) => write!(f, "Box<{}>", inner),
+            // What a reference points at depends on the environment; see
+            // `Env::describe`.
+            Type::Ref(_, false, r) => write!(f, "&{}_", r),
+            Type::Ref(_, true, r) => write!(f, "&{}mut _", r),
+            Type::Borrowed(inner, false, r) => write!(f, "&{}{}", r, inner),
+            Type::Borrowed(inner, true, r) => write!(f, "&{}mut {}", r, inner),
+            Type::Undefined(inner) => write!(f, "{} (moved)", inner),
+        }
+    }
+}
+
+impl Type {
+    pub fn boxx(inner: Type) -> Type {
+        Type::Box(Box::new(inner))
+    }
+
+    pub fn imm_ref(lval: Lval) -> Type {
+        Type::Ref(BTreeSet::from([lval]), false, Region::Inferred)
+    }
+
+    pub fn mut_ref(lval: Lval) -> Type {
+        Type::Ref(BTreeSet::from([lval]), true, Region::Inferred)
+    }
+
+    pub fn undefined(inner: Type) -> Type {
+        Type::Undefined(Box::new(inner))
+    }
+
+    // A type is defined when nothing reachable through its boxes has been moved out.
+    pub fn defined(&self) -> bool {
+        match self {
+            Type::Box(inner) => inner.defined(),
+            Type::Undefined(_) => false,
+            _ => true,
+        }
+    }
+
+    // Copy types are duplicated when read rather than moved. Like `Box`, a
+    // mutable reference owns its access and has to move. Tuples would be
+    // `Copy` when all their elements are.
+    pub fn is_copy(&self) -> bool {
+        match self {
+            Type::Unit | Type::Int | Type::Bool => true,
+            Type::Ref(_, mutable, _) | Type::Borrowed(_, mutable, _) => !mutable,
+            Type::Box(_) | Type::Undefined(_) => false,
+        }
+    }
+
+    pub fn has_refs(&self) -> bool {
+        match self {
+            Type::Box(inner) | Type::Undefined(inner) => inner.has_refs(),
+            Type::Ref(..) | Type::Borrowed(..) => true,
+            _ => false,
+        }
+    }
+
+    // Lifetime names mentioned anywhere in the type.
+    pub fn regions(&self) -> Vec<&Region> {
+        match self {
+            Type::Box(inner) | Type::Undefined(inner) => inner.regions(),
+            Type::Ref(_, _, r) => vec![r],
+            Type::Borrowed(inner, _, r) => {
+                let mut regions = inner.regions();
+                regions.push(r);
+                regions
+            }
+            _ => vec![],
+        }
+    }
+
+    // Points references to `from` at `to` instead.
+    pub fn retarget(&self, from: &str, to: &str) -> Type {
+        match self {
+            Type::Box(inner) => Type::boxx(inner.retarget(from, to)),
+            Type::Undefined(inner) => Type::undefined(inner.retarget(from, to)),
+            Type::Ref(targets, mutable, r) => {
+                let targets = targets.iter()
+                    .map(|target| if target.ident == from { Lval::new(to, target.derefs) } else { target.clone() })
+                    .collect();
+                Type::Ref(targets, *mutable, r.clone())
+            }
+            t => t.clone(),
+        }
+    }
+
+    // Rebuilds the type with the lifetime of every reference passed through `f`.
+    pub fn map_regions(&self, f: &mut impl FnMut(&Region) -> Region) -> Type {
+        match self {
+            Type::Box(inner) => Type::boxx(inner.map_regions(f)),
+            Type::Undefined(inner) => Type::undefined(inner.map_regions(f)),
+            Type::Ref(targets, mutable, r) => Type::Ref(targets.clone(), *mutable, f(r)),
+            Type::Borrowed(inner, mutable, r) => {
+                let r = f(r);
+                Type::Borrowed(Box::new(inner.map_regions(f)), *mutable, r)
+            }
+            t => t.clone(),
+        }
+    }
+
+    // Looks through boxes for the value actually held; moved-out parts hold nothing.
+    pub fn contained(&self) -> Option<&Type> {
+        match self {
+            Type::Box(inner) => inner.contained(),
+            Type::Undefined(_) => None,
+            t => Some(t),
+        }
+    }
+
+    // The places a reference held in this type may borrow.
+    pub fn targets(&self) -> impl Iterator<Item = &Lval> {
+        match self.contained() {
+            Some(Type::Ref(targets, ..)) => Some(targets.iter()),
+            _ => None,
+        }.into_iter().flatten()
+    }
+}
+
+// A reference borrowing everything either `t1` or `t2` may borrow. It only
+// keeps a lifetime name both sides agree on.
+fn merge_refs(t1: &Type, t2: &Type) -> Type {
+    match (t1, t2) {
+        (Type::Ref(w1, m1, r1), Type::Ref(w2, _, r2)) => {
+            let region = if r1 == r2 { r1.clone() } else { Region::Inferred };
+            Type::Ref(w1.union(w2).cloned().collect(), *m1, region)
+        }
+        _ => t1.clone(),
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Error {
     Dummy,
     UnboundVar(String),
     InvalidMove,
     InvalidWrite,
+    InvalidDeref(Lval),
     IncompatibleTypes(Type, Type),
+    UseAfterMove(Lval),
+    MovedBorrow(Lval),
+    ImmutableBorrow(Lval),
+    // Assigning to, or with the flag set mutably borrowing, a variable
+    // bound without `mut`.
+    ImmutableBinding(Lval, bool),
+    Dangling(Lval, Lifetime),
+    ExpiredBorrow(Lval),
+    UnboundFn(Ident),
+    DuplicateFn(Ident),
+    MissingMain,
+    ArityMismatch(Ident, usize, usize),
+    MissingLifetime(Ident),
+    UndeclaredLifetime(Ident),
+    LifetimeMismatch(Ident, Region),
+    UsedWhileMutablyBorrowed(Lval, Ident),
+    AssignedWhileBorrowed(Lval, Ident),
+    MovedWhileBorrowed(Lval, Ident),
+    BorrowedWhileMutablyBorrowed(Lval, Ident),
+    MutablyBorrowedWhileBorrowed(Lval, Ident),
+    At(Box<Error>, Span),
+}
+
+impl Located for Error {
+    fn wrap(self, span: Span) -> Error {
+        Error::At(Box::new(self), span)
+    }
+
+    fn unwrap_at(&self) -> Option<(&Error, Span)> {
+        match self {
+            Error::At(inner, span) => Some((inner, *span)),
+            _ => None,
+        }
+    }
 }
 
 pub type TypeResult<T> = Result<T, Error>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Slot {
     pub tipe: Type,
     pub lifetime: Lifetime,
+    // Whether the variable was bound with `let mut`.
+    pub mutable: bool,
+    // Whether the borrows the variable holds have ended because it is not
+    // used again. They come back to life when the variable is overwritten.
+    pub expired: bool,
 }
 
-#[derive(Clone, Debug, Default)]
-pub struct Env(pub HashMap<Ident, Slot>);
+impl Slot {
+    pub fn new(tipe: Type, lifetime: Lifetime) -> Slot {
+        Slot { tipe, lifetime, mutable: true, expired: false }
+    }
+
+    pub fn immutable(tipe: Type, lifetime: Lifetime) -> Slot {
+        Slot { tipe, lifetime, mutable: false, expired: false }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Env {
+    pub slots: HashMap<Ident, Slot>,
+    // Bindings hidden by a later `let` of the same name, as (name, hidden
+    // name) pairs, innermost last.
+    pub shadowed: Vec<(Ident, Ident)>,
+    // Every binding, in the order it was declared.
+    pub order: Vec<Ident>,
+}
 
 impl Env {
     pub fn insert(&mut self, var: &str, tipe: Type, lifetime: Lifetime) {
-        self.0.insert(var.to_string(), Slot { tipe, lifetime });
+        self.bind(var, Slot::new(tipe, lifetime));
+    }
+
+    pub fn insert_immutable(&mut self, var: &str, tipe: Type, lifetime: Lifetime) {
+        self.bind(var, Slot::immutable(tipe, lifetime));
+    }
+
+    pub fn bind(&mut self, var: &str, slot: Slot) {
+        if !self.slots.contains_key(var) {
+            self.order.push(var.to_string());
+        }
+        self.slots.insert(var.to_string(), slot);
+    }
+
+    pub fn remove(&mut self, var: &str) -> Option<Slot> {
+        self.order.retain(|v| v != var);
+        self.slots.remove(var)
     }
 
+    // The variables of lifetime `l`, most recently declared first, which is
+    // the order they are dropped in.
+    pub fn vars_by_lifetime(&self, l: &Lifetime) -> Vec<Ident> {
+        self.order.iter()
+            .rev()
+            .filter(|var| self.slots.get(*var).is_some_and(|slot| slot.lifetime == *l))
+            .cloned()
+            .collect()
+    }
+
+    // Follows `lval.derefs` through boxes and references. The lifetime is
+    // that of the variable which ultimately owns the location. Anything
+    // reached through a moved value counts as moved too.
     pub fn type_lval(&self, lval: &Lval) -> TypeResult<Slot> {
-        let Slot { tipe, lifetime } = self.0.get(&lval.ident)
-            .ok_or(Error::UnboundVar(lval.ident.clone()))?;
-        Ok(Slot {
-            tipe: tipe.clone(),
-            lifetime: lifetime.clone(), 
-        })
+        let mut slot = self.slots.get(&lval.ident)
+            .ok_or(Error::UnboundVar(lval.ident.clone()))?
+            .clone();
+        for _ in 0..lval.derefs {
+            let (tipe, moved) = match slot.tipe {
+                Type::Undefined(inner) => (*inner, true),
+                t => (t, false),
+            };
+            slot = match tipe {
+                Type::Box(inner) => Slot { tipe: *inner, ..slot },
+                Type::Ref(targets, ..) => self.type_targets(&targets)?,
+                _ => return Err(Error::InvalidDeref(lval.clone())),
+            };
+            if moved {
+                slot.tipe = Type::undefined(slot.tipe);
+            }
+        }
+        Ok(slot)
+    }
+
+    // What a reference to any of `targets` points at: the join of their types,
+    // owned for as long as the shortest-lived of them.
+    fn type_targets(&self, targets: &BTreeSet<Lval>) -> TypeResult<Slot> {
+        let mut joined: Option<Slot> = None;
+        for target in targets {
+            let slot = self.type_lval(target)?;
+            joined = Some(match joined {
+                Some(prev) => Slot {
+                    tipe: self.join_types(&prev.tipe, &slot.tipe)?,
+                    lifetime: prev.lifetime.max(slot.lifetime),
+                    mutable: prev.mutable && slot.mutable,
+                    expired: prev.expired || slot.expired,
+                },
+                None => slot,
+            });
+        }
+        joined.ok_or(Error::Dummy)
+    }
+
+    // Variables whose borrows have expired hold nothing that matters.
+    pub fn contained(&self, var: &str) -> Option<&Type> {
+        self.slots.get(var).filter(|slot| !slot.expired)?.tipe.contained()
     }
 
-    pub fn contained(&self, var: &Ident) -> Option<&Type> {
-        let mut current = &self.0.get(var)?.tipe;
-        while let Type::Undefined(inner) = current {
-            current = inner;
+    // Using a variable whose borrows have expired means liveness missed a use.
+    pub fn check_live(&self, lval: &Lval) -> TypeResult<()> {
+        match self.slots.get(&lval.ident) {
+            Some(slot) if slot.expired => Err(Error::ExpiredBorrow(Lval::var(&lval.ident))),
+            _ => Ok(()),
         }
-        Some(current)
+    }
+
+    // The variable holding a live borrow of `lval`'s root, if any. With
+    // `mutable_only` set, shared borrows are ignored.
+    pub fn borrower(&self, lval: &Lval, mutable_only: bool) -> Option<&Ident> {
+        self.slots.keys()
+            .filter(|var| match self.contained(var) {
+                Some(Type::Ref(targets, m, _)) => {
+                    targets.iter().any(|target| target.ident == lval.ident) && (*m || !mutable_only)
+                }
+                _ => false,
+            })
+            .min()
     }
 
     pub fn read_prohibited(&self, lval: &Lval) -> bool {
-        matches!(self.contained(&lval.ident), Some(Type::Undefined(_)))
+        self.borrower(lval, true).is_some()
     }
 
     pub fn write_prohibited(&self, lval: &Lval) -> bool {
-        match self.contained(&lval.ident) {
-            Some(Type::Ref(_, false)) => true,
-            Some(Type::Undefined(_)) => true,
-            _ => false,
-        }
+        self.borrower(lval, false).is_some()
     }
 
+    // Moves are only allowed out of variables and boxes they own, never
+    // through a reference.
     pub fn moove(&mut self, lval: &Lval) -> TypeResult<()> {
-        if self.read_prohibited(lval) {
+        if let Some(holder) = self.borrower(lval, false) {
+            return Err(Error::MovedWhileBorrowed(lval.clone(), holder.clone()));
+        }
+        let mut t = &self.slots.get(&lval.ident)
+            .ok_or(Error::UnboundVar(lval.ident.clone()))?
+            .tipe;
+        for _ in 0..lval.derefs {
+            t = match t {
+                Type::Box(inner) => inner,
+                _ => return Err(Error::InvalidMove),
+            };
+        }
+        if !t.defined() {
             return Err(Error::InvalidMove);
         }
-        let contained = self.contained(&lval.ident).unwrap().clone();
-        self.write(lval, Type::Undefined(Box::new(contained)))
+        let moved = Type::undefined(t.clone());
+        self.store(lval, moved)
     }
 
+    // Every reference dereferenced along the path must be mutable.
     pub fn muut(&self, lval: &Lval) -> bool {
-        matches!(self.contained(&lval.ident), Some(Type::Ref(_, true)))
+        let mut t = match self.slots.get(&lval.ident) {
+            Some(slot) => slot.tipe.clone(),
+            None => return false,
+        };
+        for _ in 0..lval.derefs {
+            t = match t {
+                Type::Box(inner) => *inner,
+                Type::Ref(targets, true, _) => {
+                    if !targets.iter().all(|target| self.muut(target)) {
+                        return false;
+                    }
+                    match self.type_targets(&targets) {
+                        Ok(slot) => slot.tipe,
+                        Err(_) => return false,
+                    }
+                }
+                _ => return false,
+            };
+        }
+        true
+    }
+
+    // Writing to `lval` without going through a reference changes the
+    // variable itself, which must then have been declared `mut`.
+    pub fn declared_mut(&self, lval: &Lval) -> bool {
+        let Some(slot) = self.slots.get(&lval.ident) else {
+            return false;
+        };
+        let mut t = &slot.tipe;
+        for _ in 0..lval.derefs {
+            t = match t {
+                Type::Box(inner) | Type::Undefined(inner) => inner,
+                _ => return true,
+            };
+        }
+        slot.mutable
     }
 
     pub fn compatible(&self, t1: &Type, t2: &Type) -> bool {
         match (t1, t2) {
+            (Type::Undefined(t1), _) => self.compatible(t1, t2),
+            (_, Type::Undefined(t2)) => self.compatible(t1, t2),
             (Type::Int, Type::Int) => true,
             (Type::Unit, Type::Unit) => true,
+            (Type::Bool, Type::Bool) => true,
             (Type::Box(b1), Type::Box(b2)) => self.compatible(b1, b2),
-            (Type::Ref(_, m1), Type::Ref(_, m2)) => m1 == m2,
+            (Type::Ref(w1, m1, _), Type::Ref(w2, m2, _)) => {
+                m1 == m2 && match (self.type_targets(w1), self.type_targets(w2)) {
+                    (Ok(s1), Ok(s2)) => self.compatible(&s1.tipe, &s2.tipe),
+                    _ => true,
+                }
+            }
+            (Type::Ref(w, m1, _), Type::Borrowed(t, m2, _)) | (Type::Borrowed(t, m2, _), Type::Ref(w, m1, _)) => {
+                m1 == m2 && self.type_targets(w).is_ok_and(|slot| self.compatible(&slot.tipe, t))
+            }
+            (Type::Borrowed(t1, m1, _), Type::Borrowed(t2, m2, _)) => m1 == m2 && self.compatible(t1, t2),
             _ => false,
         }
     }
 
+    // Rewrites `lval` into the locations it may name without going through
+    // any reference, i.e. variables followed only by box derefs. There is
+    // more than one when a reference on the way may borrow several places.
+    pub fn resolve(&self, lval: &Lval) -> TypeResult<Vec<Lval>> {
+        if lval.derefs == 0 {
+            self.slots.get(&lval.ident).ok_or(Error::UnboundVar(lval.ident.clone()))?;
+            return Ok(vec![lval.clone()]);
+        }
+        let mut paths = vec![];
+        for path in self.resolve(&Lval::new(&lval.ident, lval.derefs - 1))? {
+            match self.type_lval(&path)?.tipe {
+                Type::Box(_) => paths.push(Lval::new(&path.ident, path.derefs + 1)),
+                Type::Ref(targets, ..) => {
+                    for target in &targets {
+                        paths.extend(self.resolve(target)?);
+                    }
+                }
+                _ => return Err(Error::InvalidDeref(lval.clone())),
+            }
+        }
+        Ok(paths)
+    }
+
+    // Writing a whole value re-initialises it, even if it had been moved
+    // out of, but nothing can be written into a moved value.
     pub fn write(&mut self, w: &Lval, tipe: Type) -> TypeResult<()> {
-        if self.write_prohibited(w) {
+        if let Some(holder) = self.borrower(w, false) {
+            return Err(Error::AssignedWhileBorrowed(w.clone(), holder.clone()));
+        }
+        for derefs in 0..w.derefs {
+            let prefix = Lval::new(&w.ident, derefs);
+            if let Type::Undefined(_) = self.type_lval(&prefix)?.tipe {
+                return Err(Error::UseAfterMove(prefix));
+            }
+        }
+        if !self.muut(w) {
             return Err(Error::InvalidWrite);
         }
+        if !self.declared_mut(w) {
+            return Err(Error::ImmutableBinding(Lval::var(&w.ident), false));
+        }
+        self.store(w, tipe)
+    }
+
+    // Replaces the type at `w` without checking whether that is allowed.
+    // When `w` may name one of several locations, each of them keeps what it
+    // held before as well.
+    fn store(&mut self, w: &Lval, tipe: Type) -> TypeResult<()> {
+        let targets = self.resolve(w)?;
+        let strong = targets.len() == 1;
+        for target in targets {
+            let tipe = if strong {
+                tipe.clone()
+            } else {
+                self.join_types(&self.type_lval(&target)?.tipe, &tipe)?
+            };
+            let slot = self.slots.get_mut(&target.ident)
+                .ok_or(Error::UnboundVar(target.ident.clone()))?;
+            if target.derefs == 0 {
+                slot.expired = false;
+            }
+            let mut t = &mut slot.tipe;
+            for _ in 0..target.derefs {
+                t = match t {
+                    Type::Box(inner) => inner,
+                    _ => return Err(Error::InvalidWrite),
+                };
+            }
+            *t = tipe;
+        }
+        Ok(())
+    }
 
-        let mut_key = w.ident.clone(); 
-        let mut t = &mut self.0.get_mut(&mut_key).ok_or(Error::UnboundVar(mut_key.clone()))?.tipe;
+    // Merges the types a location may have after two branches. Anything moved
+    // on either side is moved afterwards.
+    pub fn join_types(&self, t1: &Type, t2: &Type) -> TypeResult<Type> {
+        if !self.compatible(t1, t2) {
+            return Err(Error::IncompatibleTypes(self.describe(t1), self.describe(t2)));
+        }
+        match (t1, t2) {
+            (Type::Undefined(_), _) => Ok(t1.clone()),
+            (_, Type::Undefined(_)) => Ok(t2.clone()),
+            (Type::Box(b1), Type::Box(b2)) => Ok(Type::boxx(self.join_types(b1, b2)?)),
+            // Afterwards the reference may borrow whatever either side did.
+            (Type::Ref(..), Type::Ref(..)) => Ok(merge_refs(t1, t2)),
+            _ => Ok(t1.clone()),
+        }
+    }
 
-        for _ in 0..w.derefs {
-            let inner_lval = match t {
-                Type::Ref(inner, _) => inner.ident.clone(), 
-                _ => return Err(Error::InvalidWrite),
+    // `tipe` with each reference replaced by the type it points at, for
+    // reporting it to the user.
+    pub fn describe(&self, tipe: &Type) -> Type {
+        match tipe {
+            Type::Box(inner) => Type::boxx(self.describe(inner)),
+            Type::Undefined(inner) => Type::undefined(self.describe(inner)),
+            Type::Ref(targets, mutable, r) => match self.type_targets(targets) {
+                Ok(slot) => Type::Borrowed(Box::new(self.describe(&slot.tipe)), *mutable, r.clone()),
+                Err(_) => tipe.clone(),
+            },
+            t => t.clone(),
+        }
+    }
+
+    // The environment after control flow reaches the same point via `self`
+    // or `other`; variables missing from either side are out of scope.
+    pub fn join(&self, other: &Env) -> TypeResult<Env> {
+        let mut joined = Env { shadowed: self.shadowed.clone(), ..Env::default() };
+        for var in &self.order {
+            if let (Some(slot), Some(other_slot)) = (self.slots.get(var), other.slots.get(var)) {
+                let tipe = self.join_types(&slot.tipe, &other_slot.tipe)?;
+                let expired = slot.expired || other_slot.expired;
+                joined.bind(var, Slot { tipe, expired, ..slot.clone() });
+            }
+        }
+        Ok(joined)
+    }
+
+    // The place under `target`, reached through its boxes, that holds a `tipe`.
+    pub fn project(&self, target: &Lval, tipe: &Type) -> Lval {
+        let mut path = target.clone();
+        while let Ok(slot) = self.type_lval(&path) {
+            if self.compatible(&slot.tipe, tipe) {
+                return path;
+            }
+            match slot.tipe {
+                Type::Box(_) => path.derefs += 1,
+                _ => break,
+            }
+        }
+        target.clone()
+    }
+
+    // A borrow taken through a reference outlives the reference itself, so
+    // one rooted in a variable of lifetime `l` is re-expressed in terms of
+    // whatever that variable points to.
+    pub fn reroot(&self, tipe: &Type, l: &Lifetime) -> Type {
+        match tipe {
+            Type::Box(inner) => Type::boxx(self.reroot(inner, l)),
+            Type::Ref(targets, mutable, r) => targets.iter()
+                .map(|target| self.reroot_target(target, *mutable, r, l))
+                .reduce(|t1, t2| merge_refs(&t1, &t2))
+                .unwrap_or_else(|| tipe.clone()),
+            t => t.clone(),
+        }
+    }
+
+    fn reroot_target(&self, target: &Lval, mutable: bool, r: &Region, l: &Lifetime) -> Type {
+        let unchanged = Type::Ref(BTreeSet::from([target.clone()]), mutable, r.clone());
+        let Some(slot) = self.slots.get(&target.ident).filter(|slot| slot.lifetime == *l) else {
+            return unchanged;
+        };
+        let mut t = &slot.tipe;
+        for i in 0..target.derefs {
+            t = match t {
+                Type::Box(inner) => inner,
+                Type::Ref(ws, _, r) => {
+                    let rest = target.derefs - i - 1;
+                    let ws = ws.iter().map(|w| Lval::new(&w.ident, w.derefs + rest)).collect();
+                    return self.reroot(&Type::Ref(ws, mutable, r.clone()), l);
+                }
+                _ => break,
             };
-            t = &mut self.0.get_mut(&inner_lval)
-                .ok_or(Error::UnboundVar(inner_lval.clone()))?
-                .tipe;
         }
+        unchanged
+    }
+
+    pub fn drop(&mut self, l: Lifetime) {
+        self.slots.retain(|_, slot| slot.lifetime != l);
+        let slots = &self.slots;
+        self.order.retain(|var| slots.contains_key(var));
+    }
+
+    fn rename(&mut self, from: &str, to: &str) {
+        if let Some(slot) = self.slots.remove(from) {
+            self.slots.insert(to.to_string(), slot);
+        }
+        for var in self.order.iter_mut().filter(|var| *var == from) {
+            *var = to.to_string();
+        }
+        for slot in self.slots.values_mut() {
+            slot.tipe = slot.tipe.retarget(from, to);
+        }
+    }
+
+    // Moves an existing binding of `var` out of the way of a new one. It
+    // lives on under a name programs cannot mention, and references to it
+    // follow it there.
+    pub fn shadow(&mut self, var: &str) -> Option<Ident> {
+        if !self.slots.contains_key(var) {
+            return None;
+        }
+        let depth = self.shadowed.iter().filter(|(name, _)| name == var).count() + 1;
+        let hidden = format!("{}~{}", var, depth);
+        self.rename(var, &hidden);
+        self.shadowed.push((var.to_string(), hidden.clone()));
+        Some(hidden)
+    }
+
+    // Brings back the bindings whose shadowing `let` has gone out of scope.
+    pub fn unshadow(&mut self) {
+        for i in (0..self.shadowed.len()).rev() {
+            let (var, hidden) = self.shadowed[i].clone();
+            if !self.slots.contains_key(&var) {
+                self.shadowed.remove(i);
+                self.rename(&hidden, &var);
+            }
+        }
+    }
 
-        *t = tipe;
+    // Drops lifetime `l`, then rejects any reference that still points at one
+    // of its variables, either from a surviving slot or from `result`.
+    pub fn drop_checked(&mut self, l: Lifetime, result: &Type) -> TypeResult<()> {
+        let dropped = self.vars_by_lifetime(&l);
+        self.drop(l.clone());
+        let survivors = self.order.iter()
+            .filter_map(|var| self.slots.get(var))
+            .filter(|slot| !slot.expired)
+            .map(|slot| &slot.tipe);
+        for tipe in survivors.chain(std::iter::once(result)) {
+            if let Some(target) = tipe.targets().find(|target| dropped.contains(&target.ident)) {
+                return Err(Error::Dangling(unshadowed(target), l));
+            }
+        }
         Ok(())
     }
+}
 
-    pub fn drop(&mut self, l: Lifetime) {
-        self.0.retain(|_, slot| slot.lifetime != l);
+// The place `lval` names in the program, whose binding may since have been
+// hidden by `Env::shadow`.
+fn unshadowed(lval: &Lval) -> Lval {
+    let ident = lval.ident.split('~').next().unwrap_or(&lval.ident);
+    Lval::new(ident, lval.derefs)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    pub params: Vec<Type>,
+    pub ret: Type,
+}
+
+fn region_mismatch(found: &Type, expected: &Type) -> Option<Error> {
+    match (found, expected) {
+        (Type::Box(t1), Type::Box(t2)) => region_mismatch(t1, t2),
+        (Type::Ref(_, _, r), Type::Borrowed(_, _, Region::Named(name))) if *r != Region::Named(name.clone()) => {
+            Some(Error::LifetimeMismatch(name.to_string(), r.clone()))
+        }
+        _ => None,
     }
 }
 
-#[derive(Clone, Debug)]
+// Fills in the lifetimes a signature leaves out, following Rust's elision
+// rules: each elided input gets a lifetime of its own, and if the inputs
+// have exactly one lifetime position, elided outputs get its lifetime too.
+// Two positions sharing a name still count as two. The rule
+// letting `&self` lend its lifetime to outputs would go here once there are
+// methods. Elided lifetimes are numbered, so they cannot clash with names.
+fn elide(f: &FnDef) -> TypeResult<Signature> {
+    let mut fresh = 0;
+    let params: Vec<Type> = f.params.iter()
+        .map(|(_, t)| t.map_regions(&mut |r| match r {
+            Region::Inferred => {
+                fresh += 1;
+                Region::Named(fresh.to_string().into())
+            }
+            r => r.clone(),
+        }))
+        .collect();
+    let inputs: Vec<&Region> = params.iter().flat_map(Type::regions).collect();
+    let only = match inputs[..] {
+        [only] => Some(only),
+        _ => None,
+    };
+    let ret = f.ret.map_regions(&mut |r| match (r, only) {
+        (Region::Inferred, Some(only)) => only.clone(),
+        (r, _) => r.clone(),
+    });
+    if ret.regions().contains(&&Region::Inferred) {
+        return Err(Error::MissingLifetime(f.name.clone()));
+    }
+    Ok(Signature { params, ret })
+}
+
+// Arguments are held in slots of this lifetime while a call is checked. Each
+// call names its slots apart, so a call nested in an argument only releases
+// its own.
+fn call_lifetime() -> Lifetime {
+    Lifetime(usize::MAX)
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Context {
     pub env: Env,
+    pub fns: HashMap<Ident, Signature>,
+    // Where each variable's current borrow was taken, and where each
+    // variable was last moved from, for diagnostics.
+    pub loans: HashMap<Ident, Span>,
+    pub moves: HashMap<Ident, Span>,
+    // Borrows normally end after their last use; in lexical mode they last
+    // until the end of the enclosing block instead.
+    pub lexical: bool,
+    // Variables used after the expression being checked.
+    live: HashSet<Ident>,
+    // Calls checked so far, to tell their argument slots apart.
+    calls: usize,
 }
 
 impl TypeContext {
     pub fn new() -> Self {
         TypeContext {
             env: Env::default(),
+            fns: HashMap::new(),
+            loans: HashMap::new(),
+            moves: HashMap::new(),
+            lexical: false,
+            live: HashSet::new(),
+            calls: 0,
+        }
+    }
+
+    pub fn type_program(&mut self, program: &mut Program) -> TypeResult<()> {
+        for f in &program.fns {
+            if self.fns.contains_key(&f.name) {
+                return Err(Error::DuplicateFn(f.name.clone()).at(f.span));
+            }
+            let declared = |r: &Region| match r {
+                Region::Named(name) => f.lifetimes.iter().any(|l| *l == **name),
+                Region::Inferred => true,
+            };
+            let types = f.params.iter().map(|(_, t)| t).chain(std::iter::once(&f.ret));
+            if let Some(Region::Named(name)) = types.flat_map(Type::regions).find(|r| !declared(r)) {
+                return Err(Error::UndeclaredLifetime(name.to_string()).at(f.span));
+            }
+            let sig = elide(f).map_err(|e| e.at(f.span))?;
+            self.fns.insert(f.name.clone(), sig);
+        }
+        match self.fns.get("main") {
+            Some(sig) if sig.params.is_empty() && sig.ret == Type::Unit => {}
+            _ => return Err(Error::MissingMain),
         }
+        for f in program.fns.iter_mut() {
+            self.type_fn(f)?;
+        }
+        Ok(())
+    }
+
+    // Checks a body against its signature in an environment holding only the
+    // parameters. Borrowed parameters point at placeholder slots standing in
+    // for the caller's data.
+    fn type_fn(&mut self, f: &mut FnDef) -> TypeResult<()> {
+        let sig = self.fns[&f.name].clone();
+        let outer = std::mem::take(&mut self.env);
+        self.live.clear();
+        let l = f.lifetime();
+        for ((param, _), tipe) in f.params.iter().zip(&sig.params) {
+            let tipe = self.materialise(param, tipe);
+            self.env.insert(param, tipe, l.clone());
+        }
+        let result = self.type_expr(&mut f.body)
+            .and_then(|t| self.check_return(&t, &sig.ret).map_err(|e| e.at(f.span)));
+        self.env = outer;
+        result
     }
 
-    pub fn type_stmt(&mut self, stmt: &Stmt, l: Lifetime) -> TypeResult<()> {
+    // Locals have been dropped by now, so any reference left in the result
+    // came from a parameter and must carry the lifetime the signature promises.
+    fn check_return(&self, t: &Type, ret: &Type) -> TypeResult<()> {
+        if !self.env.compatible(t, ret) {
+            return Err(self.mismatch(ret, t));
+        }
+        match region_mismatch(t, ret) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn mismatch(&self, expected: &Type, found: &Type) -> Error {
+        Error::IncompatibleTypes(self.env.describe(expected), self.env.describe(found))
+    }
+
+    fn materialise(&mut self, name: &str, tipe: &Type) -> Type {
+        match tipe {
+            Type::Borrowed(inner, mutable, region) => {
+                let target = format!("'{}", name);
+                let inner = self.materialise(&target, inner);
+                self.env.insert(&target, inner, Lifetime::global());
+                Type::Ref(BTreeSet::from([Lval::var(&target)]), *mutable, region.clone())
+            }
+            Type::Box(inner) => Type::boxx(self.materialise(name, inner)),
+            t => t.clone(),
+        }
+    }
+
+    // The caller's view of a returned reference. It may borrow from any of
+    // the arguments passed for parameters with the same lifetime.
+    fn instantiate(&self, ret: &Type, sig: &Signature, args: &[Type]) -> Type {
+        match ret {
+            Type::Box(inner) => Type::boxx(self.instantiate(inner, sig, args)),
+            Type::Borrowed(inner, mutable, region) => sig.params.iter()
+                .zip(args)
+                .filter_map(|(param, arg)| match (param, arg.contained()) {
+                    (Type::Borrowed(_, _, r), Some(Type::Ref(targets, _, arg_region))) if r == region => {
+                        Some((targets, arg_region))
+                    }
+                    _ => None,
+                })
+                .map(|(targets, r)| {
+                    let targets = targets.iter().map(|target| self.env.project(target, inner)).collect();
+                    Type::Ref(targets, *mutable, r.clone())
+                })
+                .reduce(|t1, t2| merge_refs(&t1, &t2))
+                .unwrap_or(Type::Borrowed(inner.clone(), *mutable, region.clone())),
+            t => t.clone(),
+        }
+    }
+
+    // Ends the borrows held by variables that are no longer live. A variable
+    // stays live while a live reference points into it, so a reference
+    // reborrowed from it keeps it alive.
+    fn expire(&mut self) {
+        let mut live = self.live.clone();
+        let mut pending: Vec<Ident> = live.iter().cloned().collect();
+        while let Some(var) = pending.pop() {
+            if let Some(Type::Ref(targets, ..)) = self.env.contained(&var) {
+                for target in targets {
+                    if live.insert(target.ident.clone()) {
+                        pending.push(target.ident.clone());
+                    }
+                }
+            }
+        }
+        for (var, slot) in self.env.slots.iter_mut() {
+            let scoped = slot.lifetime != Lifetime::global() && slot.lifetime != call_lifetime();
+            if scoped && !live.contains(var) && slot.tipe.has_refs() {
+                slot.expired = true;
+            }
+        }
+    }
+
+    // Re-checks the loop until the state at its head stops changing, so
+    // effects of one iteration are visible to the next.
+    fn type_loop(&mut self, cond: &mut Expr, body: &mut Expr) -> TypeResult<Type> {
+        let mut head = self.env.clone();
+        loop {
+            self.env = head.clone();
+            let t = self.type_expr(cond)?;
+            if t != Type::Bool {
+                return Err(self.mismatch(&Type::Bool, &t));
+            }
+            let exit = self.env.clone();
+            let t = self.type_expr(body)?;
+            if t != Type::Unit {
+                return Err(self.mismatch(&Type::Unit, &t));
+            }
+            let next = head.join(&self.env)?;
+            if next == head {
+                self.env = exit;
+                return Ok(Type::Unit);
+            }
+            head = next;
+        }
+    }
+
+    // Types `expr` knowing that the variables mentioned by `later`, which is
+    // evaluated after it within the same statement, are still needed.
+    fn type_before(&mut self, expr: &mut Expr, later: &[&Expr]) -> TypeResult<Type> {
+        let live_out = self.live.clone();
+        for e in later {
+            liveness::uses(e, &mut self.live);
+        }
+        let t = self.type_expr(expr);
+        self.live = live_out;
+        t
+    }
+
+    // Passing a `&mut` variable where a `&mut` is expected reborrows it, as if
+    // written `&mut *r`, so the variable can still be used after the call.
+    fn implicit_reborrow(&self, arg: &mut Expr) {
+        match arg {
+            Expr::At(inner, _) => self.implicit_reborrow(inner),
+            Expr::Lval(lval, _) => {
+                if let Ok(Slot { tipe: Type::Ref(_, true, _), .. }) = self.env.type_lval(lval) {
+                    *arg = Expr::Borrow(Lval::new(&lval.ident, lval.derefs + 1), true);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn record_loan(&mut self, var: &str, tipe: &Type, expr: &Expr) {
+        if let (Some(Type::Ref(..)), Expr::At(_, span)) = (tipe.contained(), expr) {
+            self.loans.insert(var.to_string(), *span);
+        }
+    }
+
+    pub fn type_stmt(&mut self, stmt: &mut Stmt, l: Lifetime) -> TypeResult<()> {
+        let mutable = matches!(stmt, Stmt::LetMut(..));
         match stmt {
-            Stmt::LetMut(ident, expr) => {
-                let t = self.type_expr(&mut expr.clone())?; 
-                self.env.insert(ident, t, l);
+            Stmt::Let(ident, expr) | Stmt::LetMut(ident, expr) => {
+                let mut t = self.type_expr(expr)?;
+                if let Some(hidden) = self.env.shadow(ident) {
+                    t = t.retarget(ident, &hidden);
+                    if let Some(span) = self.loans.remove(ident.as_str()) {
+                        self.loans.insert(hidden, span);
+                    }
+                }
+                self.record_loan(ident, &t, expr);
+                self.env.bind(ident, Slot { tipe: t, lifetime: l, mutable, expired: false });
                 Ok(())
             }
             Stmt::Assign(lval, expr) => {
-                let t = self.type_expr(&mut expr.clone())?; 
+                // Writing through a reference needs it to still be there.
+                let live_out = self.live.clone();
+                if lval.derefs > 0 {
+                    self.env.check_live(lval)?;
+                    self.live.insert(lval.ident.clone());
+                }
+                let t = self.type_expr(expr);
+                self.live = live_out;
+                let t = t?;
+                let old = self.env.type_lval(lval)?.tipe;
+                if !self.env.compatible(&old, &t) {
+                    return Err(self.mismatch(&old, &t));
+                }
+                if lval.derefs == 0 {
+                    self.record_loan(&lval.ident, &t, expr);
+                }
                 self.env.write(lval, t)?;
                 Ok(())
             }
             Stmt::Expr(expr) => {
-                self.type_expr(&mut expr.clone())?;
+                self.type_expr(expr)?;
                 Ok(())
             }
+            Stmt::At(inner, span) => self.type_stmt(inner, l).map_err(|e| e.at(*span)),
         }
     }
 
@@ -148,32 +989,166 @@ impl TypeContext {
         match expr {
             Expr::Unit => Ok(Type::Unit),
             Expr::Int(_) => Ok(Type::Int),
+            Expr::Bool(_) => Ok(Type::Bool),
 
             Expr::Lval(lval, copyable) => {
-                let slot = self.env.0.get(&lval.ident)
-                    .ok_or(Error::UnboundVar(lval.ident.clone()))?;
-                match &slot.tipe {
-                    Type::Int | Type::Unit => *copyable = true,
-                    _ => {}
+                self.env.check_live(lval)?;
+                if let Some(holder) = self.env.borrower(lval, true) {
+                    return Err(Error::UsedWhileMutablyBorrowed(lval.clone(), holder.clone()));
+                }
+                let tipe = self.env.type_lval(lval)?.tipe;
+                if !tipe.defined() {
+                    return Err(Error::UseAfterMove(lval.clone()));
+                }
+                if tipe.is_copy() {
+                    *copyable = true;
+                } else {
+                    self.env.moove(lval)?;
                 }
-                Ok(slot.tipe.clone())
+                Ok(tipe)
             }
 
             Expr::Box(inner) => {
                 let inner_ty = self.type_expr(inner)?;
                 Ok(Type::Box(Box::new(inner_ty)))
             }
+
+            Expr::Borrow(lval, mutable) => {
+                self.env.check_live(lval)?;
+                let slot = self.env.type_lval(lval)?;
+                if !slot.tipe.defined() {
+                    return Err(Error::MovedBorrow(lval.clone()));
+                }
+                if *mutable && !self.env.muut(lval) {
+                    return Err(Error::ImmutableBorrow(lval.clone()));
+                }
+                if *mutable && !self.env.declared_mut(lval) {
+                    return Err(Error::ImmutableBinding(Lval::var(&lval.ident), true));
+                }
+                if let Some(holder) = self.env.borrower(lval, !*mutable) {
+                    return Err(if *mutable {
+                        Error::MutablyBorrowedWhileBorrowed(lval.clone(), holder.clone())
+                    } else {
+                        Error::BorrowedWhileMutablyBorrowed(lval.clone(), holder.clone())
+                    });
+                }
+                Ok(Type::Ref(BTreeSet::from([lval.clone()]), *mutable, Region::Inferred))
+            }
+
+            Expr::Block(stmts, tail, l) => {
+                let live_out = self.live.clone();
+                let live = liveness::live_after(stmts, tail);
+                for (stmt, after) in stmts.iter_mut().zip(live) {
+                    self.live = after.union(&live_out).cloned().collect();
+                    self.type_stmt(stmt, l.clone())?;
+                    if !self.lexical {
+                        self.expire();
+                    }
+                }
+                self.live = live_out;
+                let t = self.type_expr(tail)?;
+                let t = self.env.reroot(&t, l);
+                self.env.drop_checked(l.clone(), &t)?;
+                self.env.unshadow();
+                Ok(t)
+            }
+
             Expr::AssertEq(left, right) => {
-                let t1 = self.type_expr(left)?;
+                let t1 = self.type_before(left, &[right])?;
                 let t2 = self.type_expr(right)?;
-                if t1 == Type::Int && t2 == Type::Int {
+                if matches!((&t1, &t2), (Type::Int, Type::Int) | (Type::Bool, Type::Bool)) {
                     Ok(Type::Unit)
                 } else {
-                    Err(Error::IncompatibleTypes(t1, t2))
+                    Err(self.mismatch(&t1, &t2))
+                }
+            }
+            Expr::BinOp(op, left, right) => {
+                for t in [self.type_before(left, &[right])?, self.type_expr(right)?] {
+                    if t != Type::Int {
+                        return Err(self.mismatch(&Type::Int, &t));
+                    }
+                }
+                Ok(if op.is_comparison() { Type::Bool } else { Type::Int })
+            }
+            Expr::If(cond, then, otherwise) => {
+                let t = self.type_before(cond, &[then, otherwise])?;
+                if t != Type::Bool {
+                    return Err(self.mismatch(&Type::Bool, &t));
+                }
+                let before = self.env.clone();
+                let t1 = self.type_expr(then)?;
+                let after_then = std::mem::replace(&mut self.env, before);
+                let t2 = self.type_expr(otherwise)?;
+                self.env = after_then.join(&self.env)?;
+                self.env.join_types(&t1, &t2)
+            }
+            Expr::While(cond, body) => {
+                // Variables from outside the loop that it mentions may be used
+                // again by the next iteration.
+                let live_out = self.live.clone();
+                let mut used = HashSet::new();
+                liveness::uses(cond, &mut used);
+                liveness::uses(body, &mut used);
+                self.live.extend(used.into_iter().filter(|var| self.env.slots.contains_key(var)));
+                let result = self.type_loop(cond, body);
+                self.live = live_out;
+                result
+            }
+            Expr::Call(name, args) => {
+                let sig = self.fns.get(name).cloned().ok_or(Error::UnboundFn(name.clone()))?;
+                if args.len() != sig.params.len() {
+                    return Err(Error::ArityMismatch(name.clone(), sig.params.len(), args.len()));
+                }
+                // Arguments stay live until the call, so borrows taken for
+                // later arguments must not conflict with earlier ones. A
+                // mutable borrow is only reserved until then, though: later
+                // arguments may still read through it, as with rustc's
+                // two-phase borrows.
+                let call = self.calls;
+                self.calls += 1;
+                let mut arg_types = vec![];
+                let mut temps = vec![];
+                let mut reserved = vec![];
+                let later: Vec<Expr> = args.clone();
+                for (i, (arg, param)) in args.iter_mut().zip(&sig.params).enumerate() {
+                    if matches!(param, Type::Borrowed(_, true, _)) {
+                        self.implicit_reborrow(arg);
+                    }
+                    let later: Vec<&Expr> = later[i + 1..].iter().collect();
+                    let t = self.type_before(arg, &later)?;
+                    if !self.env.compatible(&t, param) {
+                        return Err(self.mismatch(param, &t));
+                    }
+                    let temp = format!("{}#{}.{}", name, call, i);
+                    temps.push(temp.clone());
+                    match &t {
+                        Type::Ref(targets, true, r) => {
+                            self.env.insert(&temp, Type::Ref(targets.clone(), false, r.clone()), call_lifetime());
+                            reserved.push((temp, t.clone()));
+                        }
+                        _ => self.env.insert(&temp, t.clone(), call_lifetime()),
+                    }
+                    arg_types.push(t);
+                }
+                for (temp, t) in reserved {
+                    self.env.remove(&temp);
+                    if let Some(target) = t.targets().find(|target| self.env.borrower(target, false).is_some()) {
+                        return Err(Error::BorrowedWhileMutablyBorrowed(unshadowed(target), temp));
+                    }
+                    self.env.insert(&temp, t, call_lifetime());
+                }
+                for temp in temps {
+                    self.env.remove(&temp);
+                }
+                Ok(self.instantiate(&sig.ret, &sig, &arg_types))
+            }
+            Expr::At(inner, span) => {
+                let t = self.type_expr(inner).map_err(|e| e.at(*span))?;
+                if let Expr::Lval(lval, false) = inner.as_ref() {
+                    self.moves.insert(lval.ident.clone(), *span);
                 }
+                Ok(t)
             }
-    
-            _ => unimplemented!("type_expr not yet done"),
         }
     }
 }
@@ -0,0 +1,517 @@
+use crate::parser::Parser;
+use crate::types::{Error, Env, Region, Type, TypeContext};
+use crate::utils::{Expr, Lifetime, Located, Lval, Stmt};
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(src: &str) -> Result<Type, Error> {
+	let mut e = Parser::new(src).parse().unwrap();
+	TypeContext::new().type_expr(&mut e).map_err(|e| e.kind().clone())
+    }
+
+    #[test]
+    fn borrow_imm() {
+	let mut ctx = TypeContext::new();
+	ctx.env.insert("x", Type::boxx(Type::Int), Lifetime(1));
+	let mut e = Parser::new("fn main() { let mut y = &*x; }").parse().unwrap();
+	assert!(ctx.type_expr(&mut e).is_ok());
+    }
+
+    #[test]
+    fn borrow_mut_through_imm_ref() {
+	let mut env = Env::default();
+	env.insert("x", Type::Int, Lifetime(1));
+	env.insert("r", Type::imm_ref(Lval::var("x")), Lifetime(1));
+	let mut ctx = TypeContext::new();
+	ctx.env = env;
+	let mut e = Parser::new("fn main() { let mut y = &mut *r; }").parse().unwrap();
+	assert!(matches!(ctx.type_expr(&mut e).unwrap_err().kind(), Error::ImmutableBorrow(_)));
+    }
+
+    #[test]
+    fn borrow_moved() {
+	let mut ctx = TypeContext::new();
+	ctx.env.insert("x", Type::boxx(Type::undefined(Type::Int)), Lifetime(1));
+	let mut e = Parser::new("fn main() { let mut y = &x; }").parse().unwrap();
+	assert!(matches!(ctx.type_expr(&mut e).unwrap_err().kind(), Error::MovedBorrow(_)));
+    }
+
+    #[test]
+    fn block_drops_lifetime() {
+	let mut ctx = TypeContext::new();
+	let mut e = Parser::new("fn main() { let mut x = 1; let mut y = &mut x; }").parse().unwrap();
+	assert_eq!(ctx.type_expr(&mut e).unwrap(), Type::Unit);
+	assert_eq!(ctx.env, Env::default());
+    }
+
+    #[test]
+    fn nested_blocks() {
+	assert_eq!(check("fn main() { let mut x = Box::new(1); { let mut y = &x; }; }").unwrap(), Type::Unit);
+    }
+
+    #[test]
+    fn dangling_assign() {
+	let src = "fn main() { let mut x = 1; let mut r = &mut x; { let mut y = 2; r = &mut y; }; *r = 3; }";
+	assert!(matches!(check(src), Err(Error::Dangling(lval, _)) if lval == Lval::var("y")));
+    }
+
+    #[test]
+    fn dangling_through_box() {
+	let mut ctx = TypeContext::new();
+	ctx.env.insert("b", Type::boxx(Type::imm_ref(Lval::var("y"))), Lifetime(1));
+	ctx.env.insert("y", Type::Int, Lifetime(2));
+	assert!(matches!(ctx.env.drop_checked(Lifetime(2), &Type::Unit), Err(Error::Dangling(_, Lifetime(2)))));
+    }
+
+    #[test]
+    fn dangling_result() {
+	let mut ctx = TypeContext::new();
+	let mut e = Expr::Block(
+	    vec![Stmt::LetMut(String::from("y"), Expr::Int(1))],
+	    Box::new(Expr::Borrow(Lval::var("y"), false)),
+	    Lifetime(7),
+	);
+	assert!(matches!(ctx.type_expr(&mut e), Err(Error::Dangling(_, Lifetime(7)))));
+    }
+
+    #[test]
+    fn shared_borrows_coexist() {
+	assert!(check("fn main() { let mut x = 1; let mut a = &x; let mut b = &x; }").is_ok());
+    }
+
+    #[test]
+    fn mut_borrow_while_borrowed() {
+	let src = "fn main() { let mut x = 1; let mut a = &x; let mut b = &mut x; let mut c = a; }";
+	assert!(matches!(check(src), Err(Error::MutablyBorrowedWhileBorrowed(_, holder)) if holder == "a"));
+    }
+
+    #[test]
+    fn borrow_while_mutably_borrowed() {
+	let src = "fn main() { let mut x = 1; let mut a = &mut x; let mut b = &x; *a = 2; }";
+	assert!(matches!(check(src), Err(Error::BorrowedWhileMutablyBorrowed(_, holder)) if holder == "a"));
+    }
+
+    #[test]
+    fn assign_while_borrowed() {
+	let src = "fn main() { let mut x = 1; let mut a = &x; x = 2; let mut c = a; }";
+	assert!(matches!(check(src), Err(Error::AssignedWhileBorrowed(_, _))));
+    }
+
+    #[test]
+    fn move_while_borrowed() {
+	let mut env = Env::default();
+	env.insert("x", Type::boxx(Type::Int), Lifetime(1));
+	env.insert("a", Type::imm_ref(Lval::new("x", 1)), Lifetime(1));
+	assert!(matches!(env.moove(&Lval::var("x")), Err(Error::MovedWhileBorrowed(_, _))));
+    }
+
+    #[test]
+    fn write_through_shared_ref() {
+	let src = "fn main() { let mut x = 1; let mut a = &x; *a = 2; }";
+	assert!(matches!(check(src), Err(Error::InvalidWrite)));
+    }
+
+    #[test]
+    fn error_span() {
+	let src = "fn main() {\n    let mut x = 1;\n    let mut a = &mut x;\n    let mut b = &x;\n    *a = 2;\n}";
+	let mut e = Parser::new(src).parse().unwrap();
+	let err = TypeContext::new().type_expr(&mut e).unwrap_err();
+	let span = err.span().unwrap();
+	assert_eq!((span.line, span.col), (4, 17));
+	assert_eq!(&src[span.start..span.end], "&x");
+    }
+
+    #[test]
+    fn block_tail_type() {
+	assert_eq!(check("fn main() { let mut y = { let mut x = 1; x }; y }").unwrap(), Type::Int);
+    }
+
+    #[test]
+    fn block_tail_dangling() {
+	let src = "fn main() { let mut r = { let mut x = 1; &x }; }";
+	assert!(matches!(check(src), Err(Error::Dangling(lval, _)) if lval == Lval::var("x")));
+    }
+
+    #[test]
+    fn arithmetic() {
+	assert_eq!(check("fn main() { let mut x = 2; (x + 1) * 3 % 4 }").unwrap(), Type::Int);
+	assert_eq!(check("fn main() { let mut x = 2; x * 2 <= 4 }").unwrap(), Type::Bool);
+	assert!(matches!(check("fn main() { let mut x = Box::new(2); x + 1 }"), Err(Error::IncompatibleTypes(Type::Int, Type::Box(_)))));
+    }
+
+    #[test]
+    fn if_branch_types() {
+	assert_eq!(check("fn main() { let mut x = 1; if x < 2 { x } else { 3 } }").unwrap(), Type::Int);
+	assert!(matches!(check("fn main() { if true { 1 } else { false } }"), Err(Error::IncompatibleTypes(Type::Int, Type::Bool))));
+	assert!(matches!(check("fn main() { if 1 { } }"), Err(Error::IncompatibleTypes(Type::Bool, Type::Int))));
+    }
+
+    #[test]
+    fn join_moved_in_one_branch() {
+	let mut env1 = Env::default();
+	env1.insert("x", Type::boxx(Type::boxx(Type::Int)), Lifetime(1));
+	env1.insert("y", Type::Int, Lifetime(1));
+	let mut env2 = env1.clone();
+	env2.moove(&Lval::new("x", 1)).unwrap();
+	let joined = env1.join(&env2).unwrap();
+	assert_eq!(joined.slots["x"].tipe, Type::boxx(Type::undefined(Type::boxx(Type::Int))));
+	assert_eq!(joined.slots["y"].tipe, Type::Int);
+    }
+
+    #[test]
+    fn join_incompatible() {
+	let mut env1 = Env::default();
+	env1.insert("x", Type::boxx(Type::Int), Lifetime(1));
+	let mut env2 = Env::default();
+	env2.insert("x", Type::Int, Lifetime(1));
+	assert!(matches!(env1.join(&env2), Err(Error::IncompatibleTypes(_, _))));
+    }
+
+    #[test]
+    fn join_refs_to_different_places() {
+	let src = "fn main() { let a = 1; let b = 2; let c = true; let r = if c { &a } else { &b }; *r }";
+	assert_eq!(check(src).unwrap(), Type::Int);
+	let src = "fn main() { let a = 1; let b = 2; let mut r = &a; let mut i = 0; while i < 2 { r = &b; i = i + 1; }; *r }";
+	assert_eq!(check(src).unwrap(), Type::Int);
+	let src = "fn main() { let a = 1; let b = 2; let mut r = &a; if true { r = &a; } else { }; *r }";
+	assert_eq!(check(src).unwrap(), Type::Int);
+	let src = "fn main() { let a = 1; let b = true; let mut r = &a; if true { r = &b; } else { }; *r }";
+	assert!(matches!(check(src), Err(Error::IncompatibleTypes(..))));
+    }
+
+    #[test]
+    fn joined_ref_borrows_both_places() {
+	let src = "fn main() { let mut a = 1; let mut b = 2; let c = true; let r = if c { &a } else { &b }; a = 3; *r }";
+	assert!(matches!(check(src), Err(Error::AssignedWhileBorrowed(w, _)) if w == Lval::var("a")));
+	let src = "fn main() { let mut a = 1; let mut b = 2; let c = true; let r = if c { &a } else { &b }; b = 3; *r }";
+	assert!(matches!(check(src), Err(Error::AssignedWhileBorrowed(w, _)) if w == Lval::var("b")));
+	let src = "fn main() { let mut a = 1; let mut b = 2; let mut r = &a; let mut i = 0; while i < 2 { r = &b; b = 3; i = i + 1; }; *r }";
+	assert!(matches!(check(src), Err(Error::AssignedWhileBorrowed(..))));
+	let src = "fn main() { let mut a = 1; let mut b = 2; let c = true; let r = if c { &mut a } else { &mut b }; *r = 3; assert_eq!(a, 3); }";
+	assert_eq!(check(src).unwrap(), Type::Unit);
+	let src = "fn main() { let mut a = 1; let b = 2; let c = true; let r = if c { &a } else { &b }; let d = { let e = 3; if c { &e } else { r } }; *d }";
+	assert!(matches!(check(src), Err(Error::Dangling(w, _)) if w == Lval::var("e")));
+    }
+
+    #[test]
+    fn while_move_in_body() {
+	let src = "fn main() { let mut x = Box::new(1); let mut i = 0; while i < 3 { let mut y = x; i = i + 1; } }";
+	assert!(matches!(check(src), Err(Error::UseAfterMove(lval)) if lval == Lval::var("x")));
+    }
+
+    #[test]
+    fn while_reinitialised_in_body() {
+	let src = "fn main() { let mut x = Box::new(1); let mut i = 0; while i < 3 { let mut y = x; x = Box::new(2); i = i + 1; } }";
+	assert!(check(src).is_ok());
+    }
+
+    #[test]
+    fn while_moved_after_loop() {
+	let src = "fn main() { let mut x = Box::new(1); let mut b = true; while b { let mut y = x; x = Box::new(2); b = false; } let mut z = x; }";
+	assert!(check(src).is_ok());
+	let src = "fn main() { let mut x = Box::new(1); let mut b = true; while b { b = false; let mut y = x; } let mut z = x; }";
+	assert!(matches!(check(src), Err(Error::UseAfterMove(_))));
+    }
+
+    fn check_program(src: &str) -> Result<(), Error> {
+	let mut program = Parser::new(src).parse_program().unwrap();
+	TypeContext::new().type_program(&mut program).map_err(|e| e.kind().clone())
+    }
+
+    #[test]
+    fn fn_calls() {
+	let src = "fn set(r: &mut i32, v: i32) { *r = v; }\nfn main() { let mut x = 1; set(&mut x, 2); set(&mut x, 3); }";
+	assert!(check_program(src).is_ok());
+	assert!(matches!(check_program("fn main() { f(); }"), Err(Error::UnboundFn(_))));
+	assert!(matches!(check_program("fn f(a: i32) {}\nfn main() { f(); }"), Err(Error::ArityMismatch(_, 1, 0))));
+	assert!(matches!(check_program("fn f(a: bool) {}\nfn main() { f(1); }"), Err(Error::IncompatibleTypes(Type::Bool, Type::Int))));
+	assert!(matches!(check_program("fn f() -> i32 { true }\nfn main() {}"), Err(Error::IncompatibleTypes(Type::Int, Type::Bool))));
+	assert!(matches!(check_program("fn f() {}"), Err(Error::MissingMain)));
+    }
+
+    #[test]
+    fn fn_call_moves_args() {
+	let src = "fn f(b: Box<i32>) {}\nfn main() { let mut x = Box::new(1); f(x); let mut y = x; }";
+	assert!(matches!(check_program(src), Err(Error::UseAfterMove(_))));
+    }
+
+    #[test]
+    fn fn_call_conflicting_args() {
+	let src = "fn f(a: &mut i32, b: &i32) {}\nfn main() { let mut x = 1; f(&mut x, &x); }";
+	assert!(matches!(check_program(src), Err(Error::BorrowedWhileMutablyBorrowed(_, _))));
+	let src = "fn f(a: &i32, b: &i32) {}\nfn main() { let mut x = 1; f(&x, &x); let mut y = &mut x; }";
+	assert!(check_program(src).is_ok());
+    }
+
+    #[test]
+    fn fn_body_checked() {
+	let src = "fn f(r: &i32) { *r = 2; }\nfn main() {}";
+	assert!(matches!(check_program(src), Err(Error::InvalidWrite)));
+    }
+
+    const LONGEST: &str = "fn longest<'a>(x: &'a Box<i32>, y: &'a Box<i32>) -> &'a Box<i32> { if true { x } else { y } }\n";
+
+    #[test]
+    fn explicit_lifetimes() {
+	let src = format!("{}fn main() {{ let mut a = Box::new(1); let mut b = Box::new(2); let mut r = longest(&a, &b); }}", LONGEST);
+	assert!(check_program(&src).is_ok());
+	let src = "fn first<'a, 'b>(x: &'a i32, y: &'b i32) -> &'a i32 { y }\nfn main() {}";
+	assert!(matches!(check_program(src), Err(Error::LifetimeMismatch(name, _)) if name == "a"));
+	let src = "fn f(x: &'a i32) {}\nfn main() {}";
+	assert!(matches!(check_program(src), Err(Error::UndeclaredLifetime(name)) if name == "a"));
+    }
+
+    #[test]
+    fn return_ref_to_local() {
+	let src = "fn f<'a>(x: &'a i32) -> &'a i32 { let mut y = 1; &y }\nfn main() {}";
+	assert!(matches!(check_program(src), Err(Error::Dangling(lval, _)) if lval == Lval::var("y")));
+	let src = "fn f<'a>(x: Box<i32>) -> &'a Box<i32> { &x }\nfn main() {}";
+	assert!(matches!(check_program(src), Err(Error::Dangling(lval, _)) if lval == Lval::var("x")));
+    }
+
+    #[test]
+    fn returned_ref_borrows_argument() {
+	let src = format!("{}fn main() {{ let mut a = Box::new(1); let mut b = Box::new(2); let mut r = longest(&a, &b); b = Box::new(3); let mut s = r; }}", LONGEST);
+	assert!(matches!(check_program(&src), Err(Error::AssignedWhileBorrowed(lval, holder)) if lval == Lval::var("b") && holder == "r"));
+	let src = format!("{}fn main() {{ let mut a = Box::new(1); let mut r = &a; {{ let mut b = Box::new(2); r = longest(&a, &b); }}; let mut s = r; }}", LONGEST);
+	assert!(matches!(check_program(&src), Err(Error::Dangling(lval, _)) if lval == Lval::var("b")));
+    }
+
+    #[test]
+    fn returned_ref_borrows_every_argument() {
+	let longest = "fn longest<'a>(x: &'a i32, y: &'a i32) -> &'a i32 { x }\n";
+	let src = format!("{}fn main() {{ let mut a = Box::new(1); let mut b = Box::new(2); let r = longest(&*a, &*b); a = Box::new(3); assert_eq!(*r, 1); }}", longest);
+	assert!(matches!(check_program(&src), Err(Error::AssignedWhileBorrowed(lval, holder)) if lval == Lval::var("a") && holder == "r"));
+	let src = format!("{}fn main() {{ let mut a = Box::new(1); let mut b = Box::new(2); let r = longest(&*a, &*b); b = Box::new(3); assert_eq!(*r, 1); }}", longest);
+	assert!(matches!(check_program(&src), Err(Error::AssignedWhileBorrowed(lval, holder)) if lval == Lval::var("b") && holder == "r"));
+	let src = format!("{}fn main() {{ let mut a = Box::new(1); let mut b = Box::new(2); let r = longest(&*a, &*b); assert_eq!(*r, 1); a = Box::new(3); }}", longest);
+	assert!(check_program(&src).is_ok());
+    }
+
+    #[test]
+    fn elided_lifetimes() {
+	let first = "fn first(x: &Box<i32>) -> &i32 { &**x }\n";
+	assert!(check_program(&format!("{}fn main() {{}}", first)).is_ok());
+	let src = format!("{}fn main() {{ let mut b = Box::new(5); let mut r = first(&b); b = Box::new(1); let mut s = r; }}", first);
+	assert!(matches!(check_program(&src), Err(Error::AssignedWhileBorrowed(lval, _)) if lval == Lval::var("b")));
+
+	let mut program = Parser::new(&format!("{}fn main() {{}}", first)).parse_program().unwrap();
+	let mut ctx = TypeContext::new();
+	ctx.type_program(&mut program).unwrap();
+	assert_eq!(ctx.fns["first"].ret, Type::Borrowed(Box::new(Type::Int), false, Region::Named("1".into())));
+    }
+
+    #[test]
+    fn elision_ambiguous() {
+	let src = "fn f(x: &i32, y: &i32) -> &i32 { x }\nfn main() {}";
+	assert!(matches!(check_program(src), Err(Error::MissingLifetime(name)) if name == "f"));
+	assert!(matches!(check_program("fn f() -> &i32 { f() }\nfn main() {}"), Err(Error::MissingLifetime(_))));
+	let src = "fn f<'a>(x: &'a i32, y: &i32) -> &'a i32 { x }\nfn main() {}";
+	assert!(check_program(src).is_ok());
+	let src = "fn f<'a>(x: &'a i32, y: &i32) -> &i32 { x }\nfn main() {}";
+	assert!(matches!(check_program(src), Err(Error::MissingLifetime(_))));
+	let src = "fn f<'a>(x: &'a i32, y: &'a i32) -> &i32 { x }\nfn main() {}";
+	assert!(matches!(check_program(src), Err(Error::MissingLifetime(_))));
+	let src = "fn f<'a>(x: &'a Box<&'a i32>) -> &i32 { *x }\nfn main() {}";
+	assert!(matches!(check_program(src), Err(Error::MissingLifetime(_))));
+    }
+
+    #[test]
+    fn borrow_ends_after_last_use() {
+	let src = "fn main() { let mut x = 1; let mut r = &mut x; *r = 1; x = 2; }";
+	assert!(check(src).is_ok());
+	let mut e = Parser::new(src).parse().unwrap();
+	let mut ctx = TypeContext::new();
+	ctx.lexical = true;
+	assert!(matches!(ctx.type_expr(&mut e).unwrap_err().kind(), Error::AssignedWhileBorrowed(_, _)));
+    }
+
+    #[test]
+    fn borrow_live_within_statement() {
+	let srcs = [
+	    "fn main() { let mut x = 1; let mut r = &x; assert_eq!({ let mut z = 1; z }, *r); }",
+	    "fn main() { let mut x = 1; let mut r = &x; let mut y = { let mut z = 1; z } + *r; }",
+	    "fn main() { let mut x = 1; let mut r = &x; let mut y = if true { let mut z = 1; z } else { 2 } + *r; }",
+	    "fn main() { let mut x = 1; let mut r = &mut x; if { let mut z = true; z } { *r = 2; } else { }; }",
+	];
+	for src in srcs {
+	    assert!(check(src).is_ok(), "{}", src);
+	}
+	// The borrow is still live inside the block, so the write conflicts.
+	let src = "fn main() { let mut x = 1; let mut r = &x; assert_eq!({ x = 2; 1 }, *r); }";
+	assert!(matches!(check(src), Err(Error::AssignedWhileBorrowed(_, _))));
+    }
+
+    #[test]
+    fn expired_borrows_are_not_moves() {
+	let mut ctx = TypeContext::new();
+	ctx.env.insert("x", Type::Int, Lifetime(1));
+	ctx.env.insert("r", Type::mut_ref(Lval::var("x")), Lifetime(1));
+	ctx.env.slots.get_mut("r").unwrap().expired = true;
+	assert_eq!(ctx.env.slots["r"].tipe, Type::mut_ref(Lval::var("x")));
+	assert_eq!(ctx.env.borrower(&Lval::var("x"), false), None);
+	assert!(ctx.type_stmt(&mut Stmt::Assign(Lval::var("x"), Expr::Int(2)), Lifetime(1)).is_ok());
+	let err = ctx.type_expr(&mut Expr::Lval(Lval::new("r", 1), false)).unwrap_err();
+	assert!(matches!(err, Error::ExpiredBorrow(lval) if lval == Lval::var("r")));
+	ctx.env.write(&Lval::var("r"), Type::mut_ref(Lval::var("x"))).unwrap();
+	assert!(!ctx.env.slots["r"].expired);
+	assert_eq!(ctx.env.borrower(&Lval::var("x"), false), Some(&String::from("r")));
+    }
+
+    #[test]
+    fn borrow_live_across_loop() {
+	let src = "fn main() { let mut x = 1; let mut r = &mut x; let mut i = 0; while i < 2 { x = 2; *r = 1; i = i + 1; } }";
+	assert!(matches!(check(src), Err(Error::AssignedWhileBorrowed(_, _))));
+	let src = "fn main() { let mut x = 1; let mut i = 0; while i < 2 { let mut r = &mut x; *r = 1; x = 2; i = i + 1; } }";
+	assert!(check(src).is_ok());
+    }
+
+    #[test]
+    fn borrow_used_by_outer_block() {
+	let src = "fn main() { let mut x = 1; let mut r = &x; { x = 2; }; let mut s = r; }";
+	assert!(matches!(check(src), Err(Error::AssignedWhileBorrowed(_, _))));
+    }
+
+    #[test]
+    fn reborrow_freezes_original() {
+	let src = "fn main() { let mut x = 1; let mut r1 = &mut x; let mut r2 = &mut *r1; *r2 = 2; *r1 = 3; }";
+	assert!(check(src).is_ok());
+	let src = "fn main() { let mut x = 1; let mut r1 = &mut x; let mut r2 = &mut *r1; *r1 = 3; *r2 = 2; }";
+	assert!(matches!(check(src), Err(Error::AssignedWhileBorrowed(lval, holder)) if lval == Lval::new("r1", 1) && holder == "r2"));
+	let src = "fn main() { let mut x = 1; let mut r1 = &mut x; let mut r2 = &mut *r1; let mut r3 = r1; *r2 = 2; }";
+	assert!(matches!(check(src), Err(Error::UsedWhileMutablyBorrowed(_, holder)) if holder == "r2"));
+	let src = "fn main() { let mut x = 1; let mut r1 = &mut x; let mut r2 = &mut *r1; x = 3; *r2 = 2; }";
+	assert!(matches!(check(src), Err(Error::AssignedWhileBorrowed(_, holder)) if holder == "r1"));
+    }
+
+    #[test]
+    fn reborrow_through_box() {
+	let src = "fn main() { let mut x = 1; let mut b = Box::new(&mut x); let mut r = &mut **b; **b = 3; *r = 1; }";
+	assert!(matches!(check(src), Err(Error::AssignedWhileBorrowed(_, holder)) if holder == "r"));
+	let src = "fn main() { let mut x = 1; let mut b = Box::new(&mut x); let mut r = &mut **b; *r = 1; **b = 3; }";
+	assert!(check(src).is_ok());
+    }
+
+    #[test]
+    fn implicit_reborrow_in_call() {
+	let src = "fn set(r: &mut i32, v: i32) { *r = v; }\nfn main() { let mut x = 1; let mut r = &mut x; set(r, 2); set(r, 3); }";
+	assert!(check_program(src).is_ok());
+    }
+
+    #[test]
+    fn two_phase_call_args() {
+	let src = "fn set(r: &mut i32, v: i32) { *r = v; }\nfn main() { let mut x = 1; set(&mut x, x + 1); }";
+	assert!(check_program(src).is_ok());
+	let src = "fn f(a: &mut i32, b: &mut i32) {}\nfn main() { let mut x = 1; f(&mut x, &mut x); }";
+	assert!(matches!(check_program(src), Err(Error::MutablyBorrowedWhileBorrowed(_, _))));
+    }
+
+    #[test]
+    fn nested_call_args() {
+	let fns = "fn g(v: i32) -> i32 { v }\nfn f(a: &i32, b: i32, c: &mut i32) {}\nfn h(a: &mut Box<i32>, b: i32, c: Box<i32>) {}\n";
+	let src = format!("{}fn main() {{ let mut x = 1; f(&x, g(2), &mut x); }}", fns);
+	assert!(matches!(check_program(&src), Err(Error::MutablyBorrowedWhileBorrowed(_, _))));
+	let src = format!("{}fn main() {{ let mut x = Box::new(1); h(&mut x, g(2), x); }}", fns);
+	assert!(matches!(check_program(&src), Err(Error::MovedWhileBorrowed(_, _))));
+	let src = format!("{}fn main() {{ let mut x = 1; let mut y = 2; f(&x, g(g(2)), &mut y); }}", fns);
+	assert!(check_program(&src).is_ok());
+    }
+
+    #[test]
+    fn shared_refs_are_copy() {
+	assert!(Type::imm_ref(Lval::var("x")).is_copy());
+	assert!(!Type::mut_ref(Lval::var("x")).is_copy());
+	assert!(!Type::boxx(Type::Int).is_copy());
+	assert!(check("fn main() { let mut x = 1; let mut r = &x; let mut a = r; let mut b = r; }").is_ok());
+	let src = "fn main() { let mut x = 1; let mut r = &mut x; let mut a = r; let mut b = r; }";
+	assert!(matches!(check(src), Err(Error::UseAfterMove(lval)) if lval == Lval::var("r")));
+    }
+
+    #[test]
+    fn lval_through_derefs() {
+	assert_eq!(check("fn main() { let mut x = Box::new(1); assert_eq!(*x, 1); *x }").unwrap(), Type::Int);
+	assert_eq!(check("fn main() { let mut x = 1; let mut r = &x; let mut rr = &r; **rr + 1 }").unwrap(), Type::Int);
+	assert!(matches!(check("fn main() { let mut x = 1; *x }"), Err(Error::InvalidDeref(_))));
+    }
+
+    #[test]
+    fn move_out_of_deref() {
+	let src = "fn main() { let mut x = Box::new(Box::new(1)); let mut y = *x; let mut z = *x; }";
+	assert!(matches!(check(src), Err(Error::UseAfterMove(lval)) if lval == Lval::new("x", 1)));
+	let src = "fn main() { let mut x = Box::new(Box::new(1)); let mut y = *x; let mut z = x; }";
+	assert!(matches!(check(src), Err(Error::UseAfterMove(lval)) if lval == Lval::var("x")));
+	let src = "fn main() { let mut b = Box::new(1); let mut r = &b; let mut c = *r; }";
+	assert!(matches!(check(src), Err(Error::InvalidMove)));
+    }
+
+    #[test]
+    fn restore_after_partial_move() {
+	let src = "fn main() { let mut x = Box::new(Box::new(1)); let mut y = *x; *x = Box::new(2); let mut z = x; }";
+	assert!(check(src).is_ok());
+	let src = "fn main() { let mut x = Box::new(Box::new(1)); let mut y = x; *x = Box::new(2); }";
+	assert!(matches!(check(src), Err(Error::UseAfterMove(lval)) if lval == Lval::var("x")));
+	let src = "fn main() { let mut x = Box::new(Box::new(1)); let mut r = &x; **r = Box::new(2); }";
+	assert!(matches!(check(src), Err(Error::InvalidWrite)));
+    }
+
+    #[test]
+    fn restore_in_env() {
+	let mut env = Env::default();
+	env.insert("x", Type::boxx(Type::boxx(Type::Int)), Lifetime(1));
+	env.moove(&Lval::new("x", 1)).unwrap();
+	assert_eq!(env.slots["x"].tipe, Type::boxx(Type::undefined(Type::boxx(Type::Int))));
+	env.write(&Lval::new("x", 1), Type::boxx(Type::Int)).unwrap();
+	assert_eq!(env.slots["x"].tipe, Type::boxx(Type::boxx(Type::Int)));
+    }
+
+    #[test]
+    fn immutable_bindings() {
+	assert!(check("fn main() { let x = 1; let y = x + 1; let r = &x; }").is_ok());
+	assert!(matches!(check("fn main() { let x = 1; x = 2; }"), Err(Error::ImmutableBinding(_, false))));
+	assert!(matches!(check("fn main() { let x = 1; let r = &mut x; }"), Err(Error::ImmutableBinding(_, true))));
+	assert!(matches!(check("fn main() { let b = Box::new(1); *b = 2; }"), Err(Error::ImmutableBinding(lval, false)) if lval == Lval::var("b")));
+	assert!(check("fn main() { let mut x = 1; let r = &mut x; *r = 2; }").is_ok());
+	assert!(check("fn main() { let b = Box::new(1); let c = b; }").is_ok());
+    }
+
+    #[test]
+    fn shadowing() {
+	assert!(check("fn main() { let x = Box::new(1); let r = &x; let x = Box::new(2); assert_eq!(**r, 1); assert_eq!(*x, 2); }").is_ok());
+	assert_eq!(check("fn main() { let x = 1; { let x = true; }; x + 1 }").unwrap(), Type::Int);
+	assert_eq!(check("fn main() { let x = 1; let x = &x; *x }").unwrap(), Type::Int);
+	let src = "fn main() { let a = 1; let mut r = &a; { let a = 2; r = &a; }; *r }";
+	assert!(matches!(check(src), Err(Error::Dangling(lval, _)) if lval == Lval::var("a")));
+	let src = "fn main() { let a = 1; let mut r = &a; { let b = 1; r = &b; let b = 2; }; *r }";
+	assert!(matches!(check(src), Err(Error::Dangling(lval, _)) if lval == Lval::var("b")));
+    }
+
+    #[test]
+    fn shadowed_binding_stays_borrowed() {
+	let src = "fn main() { let mut x = 1; let r = &x; let x = 2; let mut y = 3; *r + y }";
+	assert!(check(src).is_ok());
+	let mut ctx = TypeContext::new();
+	ctx.env.insert("x", Type::Int, Lifetime(1));
+	ctx.env.insert("r", Type::imm_ref(Lval::var("x")), Lifetime(1));
+	assert_eq!(ctx.env.shadow("x"), Some(String::from("x~1")));
+	assert_eq!(ctx.env.slots["r"].tipe, Type::imm_ref(Lval::var("x~1")));
+	ctx.env.unshadow();
+	assert_eq!(ctx.env.slots["r"].tipe, Type::imm_ref(Lval::var("x")));
+    }
+
+    #[test]
+    fn declaration_order() {
+	let mut env = Env::default();
+	env.insert("b", Type::Int, Lifetime(1));
+	env.insert("a", Type::Int, Lifetime(1));
+	env.insert("c", Type::Int, Lifetime(2));
+	env.insert("d", Type::imm_ref(Lval::var("b")), Lifetime(1));
+	env.insert("b", Type::Bool, Lifetime(1));
+	assert_eq!(env.vars_by_lifetime(&Lifetime(1)), vec!["d", "a", "b"]);
+	env.shadow("a");
+	env.insert("a", Type::Int, Lifetime(1));
+	assert_eq!(env.vars_by_lifetime(&Lifetime(1)), vec!["a", "d", "a~1", "b"]);
+	env.drop(Lifetime(1));
+	assert_eq!(env.order, vec!["c"]);
+    }
+}
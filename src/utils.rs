@@ -1,3 +1,5 @@
+use crate::types::Type;
+
 pub type Ident = String;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -6,10 +8,53 @@ pub enum Copyable { Yes, No }
 #[derive(Clone, Debug, PartialEq)]
 pub enum Mutable { Yes, No }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Lifetime(pub usize);
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+// Byte offsets into the source plus the 1-based line and column of `start`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    // The span covering `self` through the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span { end: other.end.max(self.end), ..self }
+    }
+}
+
+// An error that can be wrapped with the source position it arose at.
+pub trait Located: Sized {
+    fn wrap(self, span: Span) -> Self;
+
+    // The wrapped error and its position, if this is a wrapper.
+    fn unwrap_at(&self) -> Option<(&Self, Span)>;
+
+    // Attaches a source position unless a more precise one is already known.
+    fn at(self, span: Span) -> Self {
+        match self.unwrap_at() {
+            Some(_) => self,
+            None => self.wrap(span),
+        }
+    }
+
+    fn kind(&self) -> &Self {
+        match self.unwrap_at() {
+            Some((inner, _)) => inner.kind(),
+            None => self,
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        self.unwrap_at().map(|(_, span)| span)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Lval {
     pub ident: Ident,
     pub derefs: usize,
@@ -28,20 +73,142 @@ impl Lval {
     }
 }
 
+impl std::fmt::Display for Lval {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}{}", "*".repeat(self.derefs), self.ident)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinOp {
+    pub fn is_comparison(self) -> bool {
+        !matches!(self, BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem)
+    }
+}
+
+impl std::fmt::Display for BinOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let symbol = match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Expr {
     Unit,
     Int(i32),
+    Bool(bool),
     Lval(Lval, bool),
     Box(Box<Expr>),
     Borrow(Lval, bool),
     Block(Vec<Stmt>, Box<Expr>, Lifetime),
     AssertEq(Box<Expr>, Box<Expr>),
+    BinOp(BinOp, Box<Expr>, Box<Expr>),
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    While(Box<Expr>, Box<Expr>),
+    Call(Ident, Vec<Expr>),
+    At(Box<Expr>, Span),
+}
+
+impl Expr {
+    // Strips any source positions wrapped around the expression.
+    pub fn unspanned(&self) -> &Expr {
+        match self {
+            Expr::At(inner, _) => inner.unspanned(),
+            e => e,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Stmt {
     Assign(Lval, Expr),
+    Let(Ident, Expr),
     LetMut(Ident, Expr),
     Expr(Expr),
+    At(Box<Stmt>, Span),
+}
+
+impl Stmt {
+    pub fn is_expr(&self) -> bool {
+        match self {
+            Stmt::At(inner, _) => inner.is_expr(),
+            s => matches!(s, Stmt::Expr(_)),
+        }
+    }
+
+    // Expression statements ending in a block need no trailing `;`.
+    pub fn is_block_like(&self) -> bool {
+        match self {
+            Stmt::At(inner, _) => inner.is_block_like(),
+            Stmt::Expr(e) => matches!(e.unspanned(), Expr::Block(..) | Expr::If(..) | Expr::While(..)),
+            _ => false,
+        }
+    }
+
+    pub fn into_expr(self) -> Expr {
+        match self {
+            Stmt::At(inner, _) => inner.into_expr(),
+            Stmt::Expr(e) => e,
+            _ => Expr::Unit,
+        }
+    }
 }
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FnDef {
+    pub name: Ident,
+    pub lifetimes: Vec<Ident>,
+    pub params: Vec<(Ident, Type)>,
+    pub ret: Type,
+    pub body: Expr,
+    pub span: Span,
+}
+
+impl FnDef {
+    // The lifetime of the function body, which parameters share.
+    pub fn lifetime(&self) -> Lifetime {
+        match self.body.unspanned() {
+            Expr::Block(_, _, l) => l.clone(),
+            _ => Lifetime::global(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    pub fns: Vec<FnDef>,
+}
+
+impl Program {
+    pub fn get(&self, name: &str) -> Option<&FnDef> {
+        self.fns.iter().find(|f| f.name == name)
+    }
+}
+